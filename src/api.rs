@@ -0,0 +1,230 @@
+// Versioned JSON API. The plain-text GET routes in main.rs stay around for existing scripts
+// and keybindings.
+//
+// `/events` streams changes as server-sent events, so status bars don't have to poll.
+
+use crate::backend::{self, Check, CheckStatus, RedshiftStatus};
+use crate::error::AppError;
+use crate::{transition, AppData, AppState, Change};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{web, FromRequest, HttpResponse};
+use futures::future::{self, Either};
+use futures::sync::mpsc::unbounded;
+use futures::{Future, Stream};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BackendHealth {
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct State {
+    /// Position on the unified brightness scale
+    pub brightness: f32,
+    /// Backlight level, as a percentage
+    pub backlight: f32,
+    /// Gamma brightness factor, from 0.0 to 1.0
+    pub gamma: f32,
+    /// Color temperature, in Kelvin
+    pub temperature: u32,
+    /// Whether a manual change is holding off the schedule
+    pub manual_override: bool,
+    pub backend: BackendHealth,
+    /// The supervised `redshift` process, when gamma goes through one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redshift: Option<RedshiftStatus>,
+}
+
+impl State {
+    pub fn from_data(data: &mut AppData) -> State {
+        // Reading the backlight back is a cheap way of checking the backend still works
+        let backend = match data.backend.read_backlight() {
+            Ok(_) => BackendHealth {
+                healthy: true,
+                error: None,
+            },
+            Err(err) => BackendHealth {
+                healthy: false,
+                error: Some(err.to_string()),
+            },
+        };
+
+        State {
+            brightness: data.brightness.value,
+            backlight: data.brightness.to_light(),
+            gamma: data.brightness.to_redshift(),
+            temperature: data.temperature.value,
+            manual_override: data.manual_override,
+            backend,
+            redshift: data.backend.redshift_status(),
+        }
+    }
+}
+
+/// Answer to `/health`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Health {
+    /// Whether every check passed, perhaps with warnings
+    pub healthy: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Health {
+    pub fn new(checks: Vec<Check>) -> Health {
+        Health {
+            healthy: checks
+                .iter()
+                .all(|check| check.status != CheckStatus::Error),
+            checks,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateUpdate {
+    brightness: Option<f32>,
+    temperature: Option<u32>,
+    /// How long the brightness change should take. Defaults to the configured duration.
+    duration_ms: Option<u64>,
+}
+
+fn get_state(data: web::Data<AppState>) -> impl Future<Item = HttpResponse, Error = AppError> {
+    data.controller
+        .call(|data| Ok(State::from_data(data)))
+        .map(|state| HttpResponse::Ok().json(state))
+}
+
+/// Applies an update, replying with the resulting state
+fn update_state(
+    update: StateUpdate,
+    data: &AppState,
+) -> impl Future<Item = HttpResponse, Error = AppError> {
+    data.controller
+        .call(move |data| {
+            if let Some(brightness) = update.brightness {
+                if !brightness.is_finite() {
+                    return Err(AppError::InvalidValue("brightness must be a number".into()));
+                }
+            }
+            if update.temperature == Some(0) {
+                return Err(AppError::InvalidValue(
+                    "temperature must be positive".into(),
+                ));
+            }
+            if update.brightness.is_none() && update.temperature.is_none() {
+                return Err(AppError::InvalidValue(
+                    "nothing to change: expected brightness and/or temperature".into(),
+                ));
+            }
+
+            data.manual_override = true;
+            if let Some(temperature) = update.temperature {
+                data.temperature.set(temperature);
+            }
+            match update.brightness {
+                Some(brightness) => {
+                    let duration = update.duration_ms.map(Duration::from_millis);
+                    transition::start(data, brightness, duration)?;
+                }
+                None => data.restart()?,
+            }
+            Ok(State::from_data(data))
+        })
+        .map(|state| HttpResponse::Ok().json(state))
+}
+
+fn put_state(
+    update: web::Json<StateUpdate>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = HttpResponse, Error = AppError> {
+    if update.brightness.is_none() || update.temperature.is_none() {
+        return Either::A(future::err(AppError::InvalidValue(
+            "PUT replaces the whole state: both brightness and temperature are required \
+             (use PATCH to change only one of them)"
+                .into(),
+        )));
+    }
+    Either::B(update_state(update.into_inner(), &data))
+}
+
+fn patch_state(
+    update: web::Json<StateUpdate>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = HttpResponse, Error = AppError> {
+    update_state(update.into_inner(), &data)
+}
+
+fn method_not_allowed() -> Result<HttpResponse, AppError> {
+    Err(AppError::MethodNotAllowed(
+        "supported methods are GET, PUT and PATCH".into(),
+    ))
+}
+
+fn event(change: Change) -> Bytes {
+    let json = serde_json::to_string(&change).expect("state serializes to JSON");
+    Bytes::from(format!("data: {}\n\n", json))
+}
+
+/// Streams the state as it changes, starting with the current one
+fn events(data: web::Data<AppState>) -> impl Future<Item = HttpResponse, Error = AppError> {
+    let (sender, receiver) = unbounded();
+    data.controller
+        .call(move |data| {
+            let _ = sender.unbounded_send(data.current());
+            data.subscribe(move |change| sender.unbounded_send(change).is_ok());
+            Ok(())
+        })
+        .map(|()| {
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .header("Cache-Control", "no-cache")
+                .streaming(
+                    receiver
+                        .map(event)
+                        .map_err(|()| -> actix_web::Error { unreachable!("receivers don't fail") }),
+                )
+        })
+}
+
+/// Probes everything the configured backend needs, then checks on the running one. Answers 503
+/// when something is broken, so it also works for plain up/down monitoring.
+fn health(state: web::Data<AppState>) -> impl Future<Item = HttpResponse, Error = AppError> {
+    let config = state.backend_config.clone();
+    // Probes run commands, which would hold up a worker
+    let probes = web::block(move || Ok::<_, ()>(backend::diagnose(&config)))
+        .map_err(|_| AppError::ShuttingDown);
+    let running = state
+        .controller
+        .call(|data| Ok(backend::check_running(&mut *data.backend)));
+    probes.join(running).map(|(mut checks, running)| {
+        checks.extend(running);
+        let health = Health::new(checks);
+        let status = if health.healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        HttpResponse::build(status).json(health)
+    })
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/api/v1/state")
+            .data(web::Json::<StateUpdate>::configure(|cfg| {
+                cfg.error_handler(|err, _| AppError::InvalidValue(err.to_string()).into())
+            }))
+            .route(web::get().to_async(get_state))
+            .route(web::put().to_async(put_state))
+            .route(web::patch().to_async(patch_state))
+            .default_service(web::to(method_not_allowed)),
+    )
+    .route("/events", web::get().to_async(events))
+    .route("/health", web::get().to_async(health));
+}
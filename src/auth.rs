@@ -0,0 +1,169 @@
+// Bearer token authentication for the control server.
+//
+// The token comes from the config file, or from a token file that gets generated (readable by
+// the owner only) the first time the daemon starts. Clients read the same file.
+
+use crate::config::ServerConfig;
+use crate::error::AppError;
+use actix_web::dev::{Body, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{Error, ResponseError};
+use failure::format_err;
+use futures::future::{ok, Either, FutureResult};
+use futures::Poll;
+use log::info;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Length of generated tokens, in random bytes
+const TOKEN_BYTES: usize = 32;
+
+fn generate_token() -> Result<String, failure::Error> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn read_token_file(path: &Path) -> Result<String, failure::Error> {
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format_err!(
+            "Token file {} is accessible by other users, fix it with 'chmod 600 {}'",
+            path.display(),
+            path.display()
+        ));
+    }
+    let token = std::fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(format_err!("Token file {} is empty", path.display()));
+    }
+    Ok(token)
+}
+
+/// Returns the token the daemon should require, generating the token file if needed. None means
+/// authentication is disabled.
+pub fn load_or_create_token(server: &ServerConfig) -> Result<Option<String>, failure::Error> {
+    if !server.auth {
+        return Ok(None);
+    }
+    if let Some(token) = &server.token {
+        return Ok(Some(token.clone()));
+    }
+
+    let path = &server.token_file;
+    if path.exists() {
+        return read_token_file(path).map(Some);
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let token = generate_token()?;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .map_err(|e| format_err!("Could not create token file {}: {}", path.display(), e))?;
+    info!("Generated a new API token in {}", path.display());
+    Ok(Some(token))
+}
+
+/// Returns the token clients should send, if any
+pub fn client_token(server: &ServerConfig) -> Result<Option<String>, failure::Error> {
+    if !server.auth {
+        return Ok(None);
+    }
+    if let Some(token) = &server.token {
+        return Ok(Some(token.clone()));
+    }
+    if !server.token_file.exists() {
+        return Ok(None);
+    }
+    read_token_file(&server.token_file).map(Some)
+}
+
+/// Compares in constant time, so response timings don't leak how much of a token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests without the right `Authorization: Bearer` header
+pub struct BearerAuth {
+    token: Option<Rc<str>>,
+}
+
+impl BearerAuth {
+    pub fn new(token: Option<&str>) -> BearerAuth {
+        BearerAuth {
+            token: token.map(Rc::from),
+        }
+    }
+}
+
+impl<S> Transform<S> for BearerAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = BearerAuthMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(BearerAuthMiddleware {
+            service,
+            token: self.token.clone(),
+        })
+    }
+}
+
+pub struct BearerAuthMiddleware<S> {
+    service: S,
+    token: Option<Rc<str>>,
+}
+
+impl<S> BearerAuthMiddleware<S> {
+    fn is_authorized(&self, req: &ServiceRequest) -> bool {
+        let expected = match &self.token {
+            Some(token) => token,
+            None => return true,
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), expected.as_bytes()))
+    }
+}
+
+impl<S> Service for BearerAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = Either<S::Future, FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.is_authorized(&req) {
+            return Either::A(self.service.call(req));
+        }
+
+        let response = AppError::Unauthorized.error_response();
+        Either::B(ok(req.into_response(response)))
+    }
+}
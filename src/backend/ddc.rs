@@ -0,0 +1,459 @@
+// External monitors, whose brightness is set over DDC/CI: the I2C side channel in the video
+// cable. The brightness is VCP (Virtual Control Panel) feature 0x10.
+//
+// Monitors are slow to answer and easily confused, so every request waits for the delays the
+// DDC/CI spec asks for. Talking to `/dev/i2c-*` needs the `i2c-dev` module loaded and
+// permission to open the device (usually membership of the `i2c` group).
+
+use super::gamma::Gamma;
+use super::{merge_outputs, Backend, OutputInfo, RedshiftStatus};
+use failure::{format_err, Error};
+use log::{debug, info};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// I2C address monitors answer DDC/CI on
+const DDC_ADDRESS: u16 = 0x37;
+/// `ioctl` selecting the I2C address further reads and writes go to, from `linux/i2c-dev.h`
+const I2C_SLAVE: libc::c_ulong = 0x0703;
+
+/// The destination address (DDC_ADDRESS, shifted for the write bit) starts the checksum of
+/// requests, and the "virtual host" address 0x50 the checksum of replies
+const REQUEST_CHECKSUM_SEED: u8 = 0x6e;
+const REPLY_CHECKSUM_SEED: u8 = 0x50;
+/// Source address byte at the start of every request
+const HOST_ADDRESS: u8 = 0x51;
+
+const GET_VCP_REQUEST: u8 = 0x01;
+const GET_VCP_REPLY: u8 = 0x02;
+const SET_VCP_REQUEST: u8 = 0x03;
+const BRIGHTNESS_VCP: u8 = 0x10;
+
+/// How long monitors need between a request and its reply, or the next request
+const REPLY_DELAY: Duration = Duration::from_millis(40);
+const SET_DELAY: Duration = Duration::from_millis(50);
+
+/// The raw I2C transfers DDC/CI is built on, so tests can stand in for a monitor
+pub trait I2c: Send {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error>;
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+/// An I2C bus under `/dev`, addressing the DDC/CI port of whatever is connected to it
+pub struct I2cDevice {
+    file: File,
+}
+
+impl I2cDevice {
+    pub fn open(path: &Path) -> Result<I2cDevice, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| format_err!("Could not open {}: {}", path.display(), err))?;
+        // Safe: I2C_SLAVE only reads its integer argument
+        let result =
+            unsafe { libc::ioctl(file.as_raw_fd(), I2C_SLAVE, DDC_ADDRESS as libc::c_ulong) };
+        if result < 0 {
+            return Err(format_err!(
+                "Could not select the DDC/CI address on {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(I2cDevice { file })
+    }
+}
+
+impl I2c for I2cDevice {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        Ok(self.file.write_all(data)?)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        Ok(self.file.read_exact(buffer)?)
+    }
+}
+
+fn checksum(seed: u8, bytes: &[u8]) -> u8 {
+    bytes.iter().fold(seed, |sum, byte| sum ^ byte)
+}
+
+/// Builds a request: source address, length (with the high bit set), payload and checksum
+fn request(payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![HOST_ADDRESS, 0x80 | payload.len() as u8];
+    message.extend_from_slice(payload);
+    message.push(checksum(REQUEST_CHECKSUM_SEED, &message));
+    message
+}
+
+/// A monitor's brightness control
+pub struct Display {
+    /// Bus name, like `i2c-4`
+    pub name: String,
+    i2c: Box<dyn I2c>,
+    /// Highest brightness value the monitor accepts, as it reported it
+    max: u16,
+}
+
+impl Display {
+    pub fn new(name: &str, i2c: Box<dyn I2c>) -> Result<Display, Error> {
+        let mut display = Display {
+            name: name.to_string(),
+            i2c,
+            max: 0,
+        };
+        let (_, max) = display.get_brightness()?;
+        if max == 0 {
+            return Err(format_err!("{} reports a maximum brightness of 0", name));
+        }
+        display.max = max;
+        Ok(display)
+    }
+
+    /// Returns the current and maximum brightness values
+    fn get_brightness(&mut self) -> Result<(u16, u16), Error> {
+        self.i2c
+            .write(&request(&[GET_VCP_REQUEST, BRIGHTNESS_VCP]))?;
+        std::thread::sleep(REPLY_DELAY);
+
+        // Source address, length, then 8 bytes of payload and the checksum
+        let mut reply = [0u8; 11];
+        self.i2c.read(&mut reply)?;
+        if checksum(REPLY_CHECKSUM_SEED, &reply[..10]) != reply[10] {
+            return Err(format_err!("Corrupted DDC/CI reply from {}", self.name));
+        }
+        let payload = &reply[2..10];
+        if payload[0] != GET_VCP_REPLY || payload[2] != BRIGHTNESS_VCP {
+            return Err(format_err!("Unexpected DDC/CI reply from {}", self.name));
+        }
+        if payload[1] != 0 {
+            return Err(format_err!(
+                "{} doesn't support brightness control",
+                self.name
+            ));
+        }
+        let max = u16::from_be_bytes([payload[4], payload[5]]);
+        let current = u16::from_be_bytes([payload[6], payload[7]]);
+        Ok((current, max))
+    }
+
+    fn set_brightness(&mut self, value: u16) -> Result<(), Error> {
+        let [high, low] = value.to_be_bytes();
+        self.i2c
+            .write(&request(&[SET_VCP_REQUEST, BRIGHTNESS_VCP, high, low]))?;
+        std::thread::sleep(SET_DELAY);
+        Ok(())
+    }
+
+    fn read_percent(&mut self) -> Result<f32, Error> {
+        let (current, _) = self.get_brightness()?;
+        Ok(current as f32 * 100.0 / self.max as f32)
+    }
+
+    fn set_percent(&mut self, percent: f32) -> Result<(), Error> {
+        let value = (percent.clamp(0.0, 100.0) / 100.0 * self.max as f32).round() as u16;
+        self.set_brightness(value)
+    }
+}
+
+/// The `/dev/i2c-*` buses wired to a display connector, going by `<sysfs_root>/class/drm`.
+/// Other buses (SMBus, sensors...) may not take kindly to DDC/CI requests, so they are left
+/// alone.
+pub(super) fn connector_buses(sysfs_root: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(sysfs_root.join("class/drm")) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut buses = Vec::new();
+    for connector in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        // Connectors are called like `card0-DP-1`, the cards themselves just `card0`
+        let is_connector = connector
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.contains('-'));
+        if !is_connector {
+            continue;
+        }
+        // The `ddc` link points at the connector's bus. DisplayPort connectors also have their
+        // AUX channel bus as a child.
+        let mut names = Vec::new();
+        if let Ok(target) = std::fs::read_link(connector.join("ddc")) {
+            names.extend(target.file_name().map(|name| name.to_os_string()));
+        }
+        if let Ok(children) = std::fs::read_dir(&connector) {
+            names.extend(
+                children
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name())
+                    .filter(|name| name.to_str().is_some_and(|name| name.starts_with("i2c-"))),
+            );
+        }
+        buses.extend(names.into_iter().map(|name| Path::new("/dev").join(name)));
+    }
+    buses.sort();
+    buses.dedup();
+    buses
+}
+
+/// Finds the buses with a monitor answering brightness requests
+fn probe(buses: &[PathBuf]) -> Vec<Display> {
+    buses
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let display =
+                I2cDevice::open(path).and_then(|device| Display::new(name, Box::new(device)));
+            match display {
+                Ok(display) => Some(display),
+                Err(err) => {
+                    // Most buses are not monitors at all
+                    debug!("No DDC/CI monitor on {}: {}", name, err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Drives external monitors over DDC/CI. The first one is the main display, the others can be
+/// adjusted as individual outputs.
+pub struct Ddc {
+    displays: Vec<Display>,
+    gamma: Box<dyn Gamma>,
+}
+
+impl Ddc {
+    /// Opens the given I2C bus, or every display connector bus with a monitor on it if none is
+    /// given
+    pub fn open(
+        device: Option<&Path>,
+        sysfs_root: &Path,
+        gamma: Box<dyn Gamma>,
+    ) -> Result<Ddc, Error> {
+        let displays = match device {
+            Some(path) => {
+                let name = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("ddc");
+                vec![Display::new(name, Box::new(I2cDevice::open(path)?))?]
+            }
+            None => probe(&connector_buses(sysfs_root)),
+        };
+        Ddc::new(displays, gamma)
+    }
+
+    pub fn new(displays: Vec<Display>, gamma: Box<dyn Gamma>) -> Result<Ddc, Error> {
+        if displays.is_empty() {
+            return Err(format_err!(
+                "No monitors with DDC/CI brightness control found"
+            ));
+        }
+        for display in &displays {
+            info!(
+                "Using DDC/CI monitor on {} (max brightness {})",
+                display.name, display.max
+            );
+        }
+        Ok(Ddc { displays, gamma })
+    }
+
+    fn display(&mut self, name: &str) -> Result<&mut Display, Error> {
+        self.displays
+            .iter_mut()
+            .find(|display| display.name == name)
+            .ok_or_else(|| format_err!("No DDC/CI monitor called {}", name))
+    }
+}
+
+impl Backend for Ddc {
+    fn read_backlight(&mut self) -> Result<f32, Error> {
+        self.displays[0].read_percent()
+    }
+
+    fn set_backlight(&mut self, percent: f32) -> Result<(), Error> {
+        self.displays[0].set_percent(percent)
+    }
+
+    fn set_gamma(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.gamma.set(brightness, temperature)
+    }
+
+    fn outputs(&mut self) -> Vec<OutputInfo> {
+        let names = self
+            .displays
+            .iter()
+            .map(|display| display.name.clone())
+            .collect();
+        merge_outputs(names, self.gamma.outputs())
+    }
+
+    fn set_output_backlight(&mut self, name: &str, percent: f32) -> Result<(), Error> {
+        self.display(name)?.set_percent(percent)
+    }
+
+    fn set_output_gamma(
+        &mut self,
+        name: &str,
+        brightness: f32,
+        temperature: u32,
+    ) -> Result<(), Error> {
+        self.gamma.set_output(name, brightness, temperature)
+    }
+
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        self.gamma.redshift_status()
+    }
+
+    fn shutdown(&mut self) {
+        self.gamma.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Answers DDC/CI brightness requests like a monitor would
+    #[derive(Clone)]
+    struct FakeMonitor {
+        brightness: Arc<Mutex<u16>>,
+        max: u16,
+        pending_reply: Option<Vec<u8>>,
+    }
+
+    impl FakeMonitor {
+        fn new(brightness: u16, max: u16) -> FakeMonitor {
+            FakeMonitor {
+                brightness: Arc::new(Mutex::new(brightness)),
+                max,
+                pending_reply: None,
+            }
+        }
+    }
+
+    impl I2c for FakeMonitor {
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            let (message, sum) = data.split_at(data.len() - 1);
+            assert_eq!(checksum(REQUEST_CHECKSUM_SEED, message), sum[0]);
+            assert_eq!(message[0], HOST_ADDRESS);
+            assert_eq!(message[1] & 0x7f, message.len() as u8 - 2);
+
+            match &message[2..] {
+                [GET_VCP_REQUEST, BRIGHTNESS_VCP] => {
+                    let [max_high, max_low] = self.max.to_be_bytes();
+                    let [high, low] = self.brightness.lock().to_be_bytes();
+                    let mut reply = vec![
+                        0x6e,
+                        0x88,
+                        GET_VCP_REPLY,
+                        0,
+                        BRIGHTNESS_VCP,
+                        0,
+                        max_high,
+                        max_low,
+                        high,
+                        low,
+                    ];
+                    reply.push(checksum(REPLY_CHECKSUM_SEED, &reply));
+                    self.pending_reply = Some(reply);
+                }
+                [SET_VCP_REQUEST, BRIGHTNESS_VCP, high, low] => {
+                    *self.brightness.lock() = u16::from_be_bytes([*high, *low]);
+                }
+                other => panic!("unexpected request {:?}", other),
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+            let reply = self.pending_reply.take().expect("read without a request");
+            buffer.copy_from_slice(&reply);
+            Ok(())
+        }
+    }
+
+    fn backend(monitors: &[(&str, &FakeMonitor)]) -> Ddc {
+        let displays = monitors
+            .iter()
+            .map(|(name, monitor)| Display::new(name, Box::new((*monitor).clone())).unwrap())
+            .collect();
+        let gamma = Box::new(crate::backend::Redshift::new(
+            "redshift",
+            "dummy",
+            std::time::Duration::from_secs(3),
+        ));
+        Ddc::new(displays, gamma).unwrap()
+    }
+
+    #[test]
+    fn reads_and_writes_percentages() {
+        let monitor = FakeMonitor::new(40, 80);
+        let mut backend = backend(&[("i2c-4", &monitor)]);
+        assert_eq!(backend.read_backlight().unwrap(), 50.0);
+
+        backend.set_backlight(25.0).unwrap();
+        assert_eq!(*monitor.brightness.lock(), 20);
+        assert_eq!(backend.read_backlight().unwrap(), 25.0);
+    }
+
+    #[test]
+    fn every_monitor_is_an_output() {
+        let first = FakeMonitor::new(100, 100);
+        let second = FakeMonitor::new(100, 100);
+        let mut backend = backend(&[("i2c-4", &first), ("i2c-7", &second)]);
+        let names: Vec<_> = backend
+            .outputs()
+            .into_iter()
+            .map(|output| output.name)
+            .collect();
+        assert_eq!(names, vec!["i2c-4", "i2c-7"]);
+
+        backend.set_output_backlight("i2c-7", 30.0).unwrap();
+        assert_eq!(*second.brightness.lock(), 30);
+        assert_eq!(*first.brightness.lock(), 100);
+        assert!(backend.set_output_backlight("i2c-9", 30.0).is_err());
+    }
+
+    #[test]
+    fn only_connector_buses_are_probed() {
+        let root = tempfile::tempdir().unwrap();
+        let drm = root.path().join("class/drm");
+        std::fs::create_dir_all(drm.join("card0")).unwrap();
+        std::fs::create_dir_all(drm.join("card0-eDP-1")).unwrap();
+        std::os::unix::fs::symlink("../../../devices/i2c-3", drm.join("card0-eDP-1/ddc")).unwrap();
+        std::fs::create_dir_all(drm.join("card0-DP-1/i2c-7")).unwrap();
+        std::fs::create_dir_all(drm.join("card0-HDMI-A-1")).unwrap();
+        std::fs::write(drm.join("version"), "drm 1.1.0\n").unwrap();
+
+        assert_eq!(
+            connector_buses(root.path()),
+            vec![PathBuf::from("/dev/i2c-3"), PathBuf::from("/dev/i2c-7")]
+        );
+        assert!(connector_buses(&root.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn rejects_corrupted_replies() {
+        struct Garbage;
+        impl I2c for Garbage {
+            fn write(&mut self, _: &[u8]) -> Result<(), Error> {
+                Ok(())
+            }
+            fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+                buffer.iter_mut().for_each(|byte| *byte = 0xff);
+                Ok(())
+            }
+        }
+        assert!(Display::new("i2c-1", Box::new(Garbage)).is_err());
+    }
+}
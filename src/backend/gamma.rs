@@ -0,0 +1,163 @@
+// Gamma adjustment: screen dimming below the backlight range, and color temperature.
+
+use super::redshift::{Redshift, RedshiftStatus};
+use super::wlr::WlrGamma;
+use failure::{format_err, Error};
+use log::{info, warn};
+use serde::Deserialize;
+use std::time::Duration;
+
+pub trait Gamma: Send {
+    /// Set the gamma brightness factor (0.0 to 1.0) and color temperature (Kelvin)
+    fn set(&mut self, brightness: f32, temperature: u32) -> Result<(), Error>;
+
+    /// Names of the outputs whose gamma can be set individually
+    fn outputs(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Set the gamma of a single output
+    fn set_output(&mut self, name: &str, _brightness: f32, _temperature: u32) -> Result<(), Error> {
+        Err(format_err!("Gamma can't be set on {} alone", name))
+    }
+
+    /// State of the supervised `redshift` process, for gamma applied through one
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        None
+    }
+
+    /// Restore the original gamma and release the session
+    fn shutdown(&mut self);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GammaKind {
+    /// Native Wayland gamma session when available, falling back to `redshift`
+    Auto,
+    /// Long-lived session through the wlr-gamma-control Wayland protocol
+    Wlr,
+    /// One `redshift` process per change
+    Redshift,
+}
+
+impl std::str::FromStr for GammaKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(GammaKind::Auto),
+            "wlr" => Ok(GammaKind::Wlr),
+            "redshift" => Ok(GammaKind::Redshift),
+            _ => Err(format_err!("Unknown gamma method '{}'", s)),
+        }
+    }
+}
+
+/// Sets up gamma adjustment, with `timeout` for every call to it
+pub fn create(
+    kind: GammaKind,
+    redshift: Redshift,
+    timeout: Duration,
+) -> Result<Box<dyn Gamma>, Error> {
+    match kind {
+        GammaKind::Redshift => Ok(Box::new(redshift)),
+        GammaKind::Wlr => Ok(Box::new(WlrGamma::connect(timeout)?)),
+        GammaKind::Auto if redshift.method() == "wayland" => match WlrGamma::connect(timeout) {
+            Ok(session) => {
+                info!("Using a native Wayland gamma session");
+                Ok(Box::new(session))
+            }
+            Err(err) => {
+                warn!("No native Wayland gamma session ({}), using redshift", err);
+                Ok(Box::new(redshift))
+            }
+        },
+        GammaKind::Auto => Ok(Box::new(redshift)),
+    }
+}
+
+/// RGB multipliers for a color temperature, normalized so 6500K is neutral. Uses Tanner
+/// Helland's fit of the blackbody color curve.
+pub fn whitepoint(temperature: u32) -> [f32; 3] {
+    fn fit(temperature: u32) -> [f32; 3] {
+        let t = temperature.clamp(1000, 40000) as f32 / 100.0;
+        let red = if t <= 66.0 {
+            255.0
+        } else {
+            329.69873 * (t - 60.0).powf(-0.13320476)
+        };
+        let green = if t <= 66.0 {
+            99.4708 * t.ln() - 161.11957
+        } else {
+            288.12216 * (t - 60.0).powf(-0.07551485)
+        };
+        let blue = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.51773 * (t - 10.0).ln() - 305.0448
+        };
+        [red, green, blue]
+    }
+
+    let color = fit(temperature);
+    let neutral = fit(6500);
+    let mut result = [0.0; 3];
+    for channel in 0..3 {
+        result[channel] = (color[channel] / neutral[channel]).clamp(0.0, 1.0);
+    }
+    result
+}
+
+/// Builds the red, green and blue ramps (one after the other, `size` entries each) for the
+/// given brightness factor and color temperature
+pub fn ramps(size: usize, brightness: f32, temperature: u32) -> Vec<u16> {
+    let whitepoint = whitepoint(temperature);
+    let mut ramps = Vec::with_capacity(size * 3);
+    for factor in whitepoint.iter() {
+        for i in 0..size {
+            let level = i as f32 / (size.max(2) - 1) as f32;
+            let value = level * brightness.clamp(0.0, 1.0) * factor;
+            ramps.push((value * f32::from(u16::MAX)).round() as u16);
+        }
+    }
+    ramps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_ramps_are_identity() {
+        let ramps = ramps(256, 1.0, 6500);
+        assert_eq!(ramps.len(), 768);
+        for channel in ramps.chunks(256) {
+            assert_eq!(channel[0], 0);
+            assert_eq!(channel[255], u16::MAX);
+            assert_eq!(channel[128], 32896);
+        }
+    }
+
+    #[test]
+    fn brightness_scales_every_channel() {
+        let ramps = ramps(256, 0.5, 6500);
+        for channel in ramps.chunks(256) {
+            assert_eq!(channel[255], 32768);
+        }
+    }
+
+    #[test]
+    fn warm_temperatures_cut_blue_first() {
+        let [red, green, blue] = whitepoint(3500);
+        assert_eq!(red, 1.0);
+        assert!(green < red);
+        assert!(blue < green);
+
+        let [red, _, blue] = whitepoint(9000);
+        assert!(red < 1.0);
+        assert_eq!(blue, 1.0);
+    }
+}
@@ -0,0 +1,475 @@
+// Diagnostics for `/health` and `sunset doctor`: probes every piece the configured backend
+// relies on (commands, devices, gamma methods), and says what to do about the ones missing.
+//
+// Probes only look: nothing here changes the backlight or gamma, so they can run next to a
+// working daemon.
+
+use super::ddc::{connector_buses, I2cDevice};
+use super::gamma::GammaKind;
+use super::sysfs::{list_devices, read_max_brightness, read_value};
+use super::{run, wlr, Backend, BackendConfig, BackendKind};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    /// Works, but not as configured or not as well as it could
+    Warning,
+    Error,
+}
+
+/// Outcome of one probe
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Check {
+    /// What was checked, like `light` or `gamma`
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// What to do about a problem
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, detail: impl Into<String>) -> Check {
+        Check {
+            name: name.into(),
+            status: CheckStatus::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warning(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> Check {
+        Check {
+            name: name.into(),
+            status: CheckStatus::Warning,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn error(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> Check {
+        Check {
+            name: name.into(),
+            status: CheckStatus::Error,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Probes everything `config` needs
+pub fn diagnose(config: &BackendConfig) -> Vec<Check> {
+    let mut checks = match config.kind {
+        BackendKind::LightRedshift => check_light(config),
+        BackendKind::Sysfs => vec![check_sysfs(config)],
+        BackendKind::Ddc => vec![check_ddc(config)],
+    };
+    checks.extend(check_gamma(config));
+    checks
+}
+
+/// Checks on a running backend: whether it still answers, and how its redshift process is doing
+pub fn check_running(backend: &mut dyn Backend) -> Vec<Check> {
+    let mut checks = vec![match backend.read_backlight() {
+        Ok(percent) => Check::ok("backend", format!("Backlight at {:.1}%", percent)),
+        Err(err) => Check::error(
+            "backend",
+            err.to_string(),
+            "See the other checks for what the backend is missing",
+        ),
+    }];
+
+    if let Some(redshift) = backend.redshift_status() {
+        let restarts = match redshift.restarts {
+            0 => String::new(),
+            1 => ", restarted once".into(),
+            n => format!(", restarted {} times", n),
+        };
+        checks.push(match (redshift.pid, redshift.last_error) {
+            (Some(pid), None) => Check::ok("redshift process", format!("Running (pid {})", pid)),
+            (None, None) => Check::ok("redshift process", "Not running"),
+            (Some(pid), Some(err)) => Check::warning(
+                "redshift process",
+                format!("Running (pid {}){}, last error: {}", pid, restarts, err),
+                "redshift failed before, the daemon log has its full output",
+            ),
+            (None, Some(err)) => Check::error(
+                "redshift process",
+                format!("{}{}", err, restarts),
+                "Run the redshift command from the daemon log by hand to see why it fails",
+            ),
+        });
+    }
+    checks
+}
+
+/// First line a command prints for `flag`, which for most tools is the version
+fn version(command: &str, flag: &str, config: &BackendConfig) -> Result<String, Error> {
+    let output = run(Command::new(command).arg(flag), config.command_timeout)?;
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("unknown version")
+        .to_string())
+}
+
+fn check_light(config: &BackendConfig) -> Vec<Check> {
+    let command = &config.light_command;
+    let version = match version(command, "-V", config) {
+        Ok(version) => version,
+        Err(err) => {
+            return vec![Check::error(
+                "light",
+                err.to_string(),
+                "Install light, or point backend.light_command at it",
+            )]
+        }
+    };
+
+    let read = run(Command::new(command).arg("-G"), config.command_timeout)
+        .and_then(|output| Ok(String::from_utf8_lossy(&output).trim().parse::<f32>()?));
+    vec![
+        Check::ok("light", format!("{} {}", command, version)),
+        match read {
+            Ok(percent) => Check::ok("backlight", format!("light reads {:.1}%", percent)),
+            Err(err) => Check::error(
+                "backlight",
+                format!("light could not read the backlight: {}", err),
+                "light needs a device under /sys/class/backlight it can write to: add yourself \
+                 to the video group and install light's udev rules",
+            ),
+        },
+    ]
+}
+
+fn check_sysfs(config: &BackendConfig) -> Check {
+    let class_path = config.sysfs_root.join("class/backlight");
+    let device_path = match &config.backlight_device {
+        Some(device) => class_path.join(device),
+        None => match list_devices(&class_path).map(|devices| devices.into_iter().next()) {
+            Ok(Some(device_path)) => device_path,
+            Ok(None) | Err(_) => {
+                return Check::error(
+                    "backlight",
+                    format!("No backlight devices in {}", class_path.display()),
+                    "External monitors have no backlight device: use backend.kind = \"ddc\" \
+                     for them",
+                )
+            }
+        },
+    };
+    if !device_path.exists() {
+        let available = list_devices(&class_path)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        return Check::error(
+            "backlight",
+            format!("No backlight device at {}", device_path.display()),
+            if available.is_empty() {
+                "Unset backend.backlight_device, or plug in the screen it names".to_string()
+            } else {
+                format!(
+                    "Set backend.backlight_device to one of: {}",
+                    available.join(", ")
+                )
+            },
+        );
+    }
+
+    let brightness_path = device_path.join("brightness");
+    let read =
+        read_max_brightness(&device_path).and_then(|max| Ok((read_value(&brightness_path)?, max)));
+    let (brightness, max) = match read {
+        Ok(read) => read,
+        Err(err) => {
+            return Check::error(
+                "backlight",
+                err.to_string(),
+                "Check that the backlight driver is loaded and working",
+            )
+        }
+    };
+    // Opening for writing is enough to find out about permissions, without changing anything
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .open(&brightness_path)
+    {
+        Ok(_) => Check::ok(
+            "backlight",
+            format!(
+                "{} at {}/{}, writable",
+                device_path.display(),
+                brightness,
+                max
+            ),
+        ),
+        Err(err) => Check::error(
+            "backlight",
+            format!("Can't write {}: {}", brightness_path.display(), err),
+            "Install a udev rule making the brightness file writable by the video group, and \
+             add yourself to that group",
+        ),
+    }
+}
+
+fn check_ddc(config: &BackendConfig) -> Check {
+    let device = config.ddc_device.as_deref();
+    let buses = match device {
+        Some(device) => vec![device.into()],
+        None => connector_buses(&config.sysfs_root),
+    };
+    if buses.is_empty() {
+        return Check::error(
+            "ddc",
+            format!(
+                "No display connector in {} has an I2C bus",
+                config.sysfs_root.join("class/drm").display()
+            ),
+            "Set backend.ddc_device to the bus of your monitor",
+        );
+    }
+
+    let mut failures = Vec::new();
+    for bus in &buses {
+        if let Err(err) = I2cDevice::open(bus) {
+            failures.push(err.to_string());
+        }
+    }
+    if failures.len() == buses.len() {
+        return Check::error(
+            "ddc",
+            failures.join("; "),
+            if failures.iter().any(|err| err.contains("ermission")) {
+                "Add yourself to the i2c group (or whichever group owns /dev/i2c-*)"
+            } else if failures.iter().any(|err| err.contains("No such file")) {
+                "Load the i2c-dev module: modprobe i2c-dev"
+            } else {
+                "Set backend.ddc_device to the bus of your monitor"
+            },
+        );
+    }
+    Check::ok(
+        "ddc",
+        format!(
+            "{} of {} I2C buses can be opened; monitors are looked for when the daemon starts",
+            buses.len() - failures.len(),
+            buses.len()
+        ),
+    )
+}
+
+fn check_gamma(config: &BackendConfig) -> Vec<Check> {
+    let native = match config.gamma {
+        GammaKind::Redshift => None,
+        GammaKind::Auto if config.redshift_method != "wayland" => None,
+        GammaKind::Wlr | GammaKind::Auto => Some(wlr::probe()),
+    };
+    match native {
+        Some(Ok(outputs)) => vec![Check::ok(
+            "gamma",
+            format!(
+                "Native Wayland gamma control, compositor has {} outputs",
+                outputs
+            ),
+        )],
+        Some(Err(err)) if config.gamma == GammaKind::Wlr => vec![Check::error(
+            "gamma",
+            err.to_string(),
+            "Native gamma needs a wlroots-based compositor (like sway): set backend.gamma = \
+             \"redshift\" otherwise",
+        )],
+        Some(Err(err)) => {
+            let mut checks = vec![Check::warning(
+                "gamma",
+                format!(
+                    "{}, falling back to redshift",
+                    err.to_string().trim_end_matches('.')
+                ),
+                "Native gamma needs a wlroots-based compositor (like sway), and WAYLAND_DISPLAY \
+                 set for the daemon",
+            )];
+            checks.extend(check_redshift(config));
+            checks
+        }
+        None => check_redshift(config),
+    }
+}
+
+fn check_redshift(config: &BackendConfig) -> Vec<Check> {
+    let command = &config.redshift_command;
+    let version = match version(command, "-V", config) {
+        Ok(version) => version,
+        Err(err) => {
+            return vec![Check::error(
+                "redshift",
+                err.to_string(),
+                "Install redshift, or point backend.redshift_command at it",
+            )]
+        }
+    };
+    let mut checks = vec![Check::ok("redshift", version)];
+
+    // Methods can come with options, like `randr:screen=1`
+    let method = config.redshift_method.split(':').next().unwrap_or_default();
+    checks.push(
+        match run(
+            Command::new(command).args(["-m", "list"]),
+            config.command_timeout,
+        ) {
+            Ok(output) => {
+                let methods = parse_methods(&String::from_utf8_lossy(&output));
+                if methods.iter().any(|available| available == method) {
+                    Check::ok("gamma", format!("redshift supports the {} method", method))
+                } else if methods.is_empty() {
+                    Check::warning(
+                        "gamma",
+                        "redshift didn't list any adjustment methods",
+                        format!("Check that `{} -m list` works", command),
+                    )
+                } else {
+                    Check::error(
+                        "gamma",
+                        format!("redshift doesn't support the {} method", method),
+                        format!(
+                            "Set backend.redshift_method to one of: {}",
+                            methods.join(", ")
+                        ),
+                    )
+                }
+            }
+            Err(err) => Check::warning(
+                "gamma",
+                format!("Could not list redshift's methods: {}", err),
+                format!("Check that `{} -m list` works", command),
+            ),
+        },
+    );
+    checks
+}
+
+/// Picks the method names out of `redshift -m list`, which lists them indented under a heading
+fn parse_methods(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with(' ') || line.starts_with('\t'))
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.contains(' '))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn script(dir: &std::path::Path, name: &str, body: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn statuses(checks: &[Check]) -> Vec<(&str, CheckStatus)> {
+        checks
+            .iter()
+            .map(|check| (check.name.as_str(), check.status))
+            .collect()
+    }
+
+    #[test]
+    fn redshift_methods_are_parsed() {
+        let output = "Available adjustment methods:\n  drm\n  randr\n  vidmode\n  dummy\n\n\
+                      Specify colon-separated options with `-m METHOD:OPTIONS`.\n\
+                      Try `-m METHOD:help' for help.\n";
+        assert_eq!(parse_methods(output), ["drm", "randr", "vidmode", "dummy"]);
+    }
+
+    #[test]
+    fn unsupported_redshift_method_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let redshift = script(
+            dir.path(),
+            "redshift",
+            r#"case "$1" in
+                -V) echo "redshift 1.12" ;;
+                -m) printf 'Available adjustment methods:\n  randr\n  dummy\n' ;;
+            esac"#,
+        );
+        let mut config = BackendConfig {
+            gamma: GammaKind::Redshift,
+            redshift_command: redshift,
+            redshift_method: "randr:screen=0".into(),
+            ..BackendConfig::default()
+        };
+        let checks = check_gamma(&config);
+        assert_eq!(
+            statuses(&checks),
+            [("redshift", CheckStatus::Ok), ("gamma", CheckStatus::Ok)]
+        );
+        assert_eq!(checks[0].detail, "redshift 1.12");
+
+        config.redshift_method = "wayland".into();
+        let checks = check_gamma(&config);
+        assert_eq!(checks[1].status, CheckStatus::Error);
+        assert_eq!(
+            checks[1].hint.as_deref(),
+            Some("Set backend.redshift_method to one of: randr, dummy")
+        );
+    }
+
+    #[test]
+    fn missing_commands_are_reported() {
+        let config = BackendConfig {
+            light_command: "/nonexistent/light".into(),
+            gamma: GammaKind::Redshift,
+            redshift_command: "/nonexistent/redshift".into(),
+            ..BackendConfig::default()
+        };
+        let checks = diagnose(&config);
+        assert_eq!(
+            statuses(&checks),
+            [
+                ("light", CheckStatus::Error),
+                ("redshift", CheckStatus::Error)
+            ]
+        );
+        assert!(checks[0]
+            .detail
+            .starts_with("Could not run /nonexistent/light"));
+    }
+
+    #[test]
+    fn sysfs_devices_are_checked() {
+        let root = tempfile::tempdir().unwrap();
+        let device = root.path().join("class/backlight/intel_backlight");
+        std::fs::create_dir_all(&device).unwrap();
+        std::fs::write(device.join("max_brightness"), "1000\n").unwrap();
+        std::fs::write(device.join("brightness"), "400\n").unwrap();
+
+        let mut config = BackendConfig {
+            kind: BackendKind::Sysfs,
+            sysfs_root: root.path().into(),
+            ..BackendConfig::default()
+        };
+        let check = check_sysfs(&config);
+        assert_eq!(check.status, CheckStatus::Ok, "{:?}", check);
+        assert!(check.detail.ends_with("at 400/1000, writable"));
+
+        config.backlight_device = Some("acpi_video0".into());
+        let check = check_sysfs(&config);
+        assert_eq!(check.status, CheckStatus::Error);
+        assert_eq!(
+            check.hint.as_deref(),
+            Some("Set backend.backlight_device to one of: intel_backlight")
+        );
+    }
+}
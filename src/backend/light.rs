@@ -0,0 +1,67 @@
+use super::gamma::Gamma;
+use super::{merge_outputs, run, Backend, OutputInfo, RedshiftStatus};
+use failure::Error;
+use log::debug;
+use std::process::Command;
+use std::time::Duration;
+
+/// Drives the backlight through the `light` command
+pub struct LightRedshift {
+    light_command: String,
+    timeout: Duration,
+    gamma: Box<dyn Gamma>,
+}
+
+impl LightRedshift {
+    pub fn new(light_command: &str, timeout: Duration, gamma: Box<dyn Gamma>) -> LightRedshift {
+        LightRedshift {
+            light_command: light_command.into(),
+            timeout,
+            gamma,
+        }
+    }
+}
+
+impl Backend for LightRedshift {
+    fn read_backlight(&mut self) -> Result<f32, Error> {
+        let output = run(&mut Command::new(&self.light_command), self.timeout)?;
+        let output_str = std::str::from_utf8(&output)?;
+        debug!("light output: {}", output_str.trim());
+        Ok(output_str.trim().parse()?)
+    }
+
+    fn set_backlight(&mut self, percent: f32) -> Result<(), Error> {
+        run(
+            Command::new(&self.light_command)
+                .arg("-S")
+                .arg(format!("{}", percent)),
+            self.timeout,
+        )?;
+        Ok(())
+    }
+
+    fn set_gamma(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.gamma.set(brightness, temperature)
+    }
+
+    fn outputs(&mut self) -> Vec<OutputInfo> {
+        merge_outputs(Vec::new(), self.gamma.outputs())
+    }
+
+    fn set_output_gamma(
+        &mut self,
+        name: &str,
+        brightness: f32,
+        temperature: u32,
+    ) -> Result<(), Error> {
+        self.gamma.set_output(name, brightness, temperature)
+    }
+
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        self.gamma.redshift_status()
+    }
+
+    fn shutdown(&mut self) {
+        self.gamma.shutdown();
+    }
+}
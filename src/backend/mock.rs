@@ -0,0 +1,145 @@
+use super::{Backend, OutputInfo, RedshiftStatus};
+use crate::error::AppError;
+use failure::Error;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    SetBacklight(f32),
+    SetGamma(f32, u32),
+    SetOutputBacklight(String, f32),
+    SetOutputGamma(String, f32, u32),
+    Shutdown,
+}
+
+/// Fake backend that records every call made to it. Clones share the same recording, so a test
+/// can keep one clone around while the daemon owns the other.
+#[derive(Clone, Default)]
+pub struct Mock {
+    backlight: Arc<Mutex<f32>>,
+    calls: Arc<Mutex<Vec<Call>>>,
+    outputs: Vec<OutputInfo>,
+    /// Returned by every change while set, instead of recording it
+    failure: Arc<Mutex<Option<AppError>>>,
+    redshift: Option<RedshiftStatus>,
+    /// Output that is also the main backlight device
+    main_output: Option<String>,
+}
+
+impl Mock {
+    pub fn new(backlight: f32) -> Mock {
+        Mock {
+            backlight: Arc::new(Mutex::new(backlight)),
+            calls: Arc::default(),
+            outputs: Vec::new(),
+            failure: Arc::default(),
+            redshift: None,
+            main_output: None,
+        }
+    }
+
+    /// Reports outputs that can be adjusted individually
+    pub fn with_outputs(mut self, outputs: &[(&str, bool, bool)]) -> Mock {
+        self.outputs = outputs
+            .iter()
+            .map(|&(name, backlight, gamma)| OutputInfo {
+                name: name.to_string(),
+                backlight,
+                gamma,
+            })
+            .collect();
+        self
+    }
+
+    /// Makes `name` the main backlight device, so setting it on its own changes the backlight
+    pub fn with_main_output(mut self, name: &str) -> Mock {
+        self.main_output = Some(name.to_string());
+        self
+    }
+
+    /// Reports a supervised redshift process
+    pub fn with_redshift(mut self, status: RedshiftStatus) -> Mock {
+        self.redshift = Some(status);
+        self
+    }
+
+    /// Changes the backlight the way another program would, without recording a call
+    pub fn change_backlight(&self, percent: f32) {
+        *self.backlight.lock() = percent;
+    }
+
+    /// Makes changes fail with `error`, or work again with `None`
+    pub fn fail_with(&self, error: Option<AppError>) {
+        *self.failure.lock() = error;
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        match &*self.failure.lock() {
+            Some(error) => Err(error.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the calls recorded so far, clearing the recording
+    pub fn take_calls(&self) -> Vec<Call> {
+        std::mem::take(&mut *self.calls.lock())
+    }
+}
+
+impl Backend for Mock {
+    fn read_backlight(&mut self) -> Result<f32, Error> {
+        Ok(*self.backlight.lock())
+    }
+
+    fn set_backlight(&mut self, percent: f32) -> Result<(), Error> {
+        self.check()?;
+        *self.backlight.lock() = percent;
+        self.calls.lock().push(Call::SetBacklight(percent));
+        Ok(())
+    }
+
+    fn set_gamma(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.check()?;
+        self.calls
+            .lock()
+            .push(Call::SetGamma(brightness, temperature));
+        Ok(())
+    }
+
+    fn outputs(&mut self) -> Vec<OutputInfo> {
+        self.outputs.clone()
+    }
+
+    fn set_output_backlight(&mut self, name: &str, percent: f32) -> Result<(), Error> {
+        if self.main_output.as_deref() == Some(name) {
+            *self.backlight.lock() = percent;
+        }
+        self.calls
+            .lock()
+            .push(Call::SetOutputBacklight(name.to_string(), percent));
+        Ok(())
+    }
+
+    fn set_output_gamma(
+        &mut self,
+        name: &str,
+        brightness: f32,
+        temperature: u32,
+    ) -> Result<(), Error> {
+        self.calls.lock().push(Call::SetOutputGamma(
+            name.to_string(),
+            brightness,
+            temperature,
+        ));
+        Ok(())
+    }
+
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        self.redshift.clone()
+    }
+
+    fn shutdown(&mut self) {
+        self.calls.lock().push(Call::Shutdown);
+    }
+}
@@ -0,0 +1,354 @@
+// Display backends: the pieces of the system that actually change the screen.
+
+use crate::error::AppError;
+use failure::{format_err, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+mod ddc;
+mod gamma;
+mod health;
+mod light;
+#[cfg(test)]
+pub mod mock;
+mod redshift;
+mod sysfs;
+mod wlr;
+
+pub use self::ddc::Ddc;
+pub use self::gamma::GammaKind;
+pub use self::health::{check_running, diagnose, Check, CheckStatus};
+pub use self::light::LightRedshift;
+pub use self::redshift::{Redshift, RedshiftStatus};
+pub use self::sysfs::Sysfs;
+
+pub trait Backend: Send {
+    /// Current backlight level, as a percentage
+    fn read_backlight(&mut self) -> Result<f32, Error>;
+
+    /// Set the backlight level, as a percentage
+    fn set_backlight(&mut self, percent: f32) -> Result<(), Error>;
+
+    /// Set the gamma brightness factor (0.0 to 1.0) and color temperature (Kelvin)
+    fn set_gamma(&mut self, brightness: f32, temperature: u32) -> Result<(), Error>;
+
+    /// Outputs that can also be adjusted one at a time
+    fn outputs(&mut self) -> Vec<OutputInfo> {
+        Vec::new()
+    }
+
+    /// Set the backlight level of a single output, as a percentage
+    fn set_output_backlight(&mut self, name: &str, _percent: f32) -> Result<(), Error> {
+        Err(format_err!("{} has no backlight of its own", name))
+    }
+
+    /// Set the gamma brightness factor and color temperature of a single output
+    fn set_output_gamma(
+        &mut self,
+        name: &str,
+        _brightness: f32,
+        _temperature: u32,
+    ) -> Result<(), Error> {
+        Err(format_err!("Gamma can't be set on {} alone", name))
+    }
+
+    /// State of the supervised `redshift` process, if the backend runs one
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        None
+    }
+
+    /// Release any resources (processes, devices) held by the backend
+    fn shutdown(&mut self);
+}
+
+/// A screen, or the part of one, that can be adjusted on its own
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OutputInfo {
+    pub name: String,
+    /// Whether it has a backlight, for the part of the scale above the pivot
+    pub backlight: bool,
+    /// Whether it has gamma tables, for the part of the scale below the pivot
+    pub gamma: bool,
+}
+
+/// Merges backlight devices and gamma outputs into a single list. A device and an output with
+/// the same name are taken to be the same screen.
+fn merge_outputs(backlights: Vec<String>, gamma_outputs: Vec<String>) -> Vec<OutputInfo> {
+    let mut outputs: Vec<OutputInfo> = backlights
+        .into_iter()
+        .map(|name| OutputInfo {
+            name,
+            backlight: true,
+            gamma: false,
+        })
+        .collect();
+    for name in gamma_outputs {
+        match outputs.iter_mut().find(|output| output.name == name) {
+            Some(output) => output.gamma = true,
+            None => outputs.push(OutputInfo {
+                name,
+                backlight: false,
+                gamma: true,
+            }),
+        }
+    }
+    outputs
+}
+
+/// Error for a command that couldn't be started at all
+fn spawn_error(program: &str, err: std::io::Error) -> Error {
+    AppError::BackendUnavailable(format!("Could not run {}: {}", program, err)).into()
+}
+
+/// How often a running command is checked on
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Reads a pipe to the end on its own thread, so a command can't stall on a full pipe
+fn read_all(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+/// Runs a command to completion, returning what it printed. Commands still running after
+/// `timeout` get killed.
+fn run(command: &mut Command, timeout: Duration) -> Result<Vec<u8>, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| spawn_error(&program, err))?;
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "{} still running after {} ms, killing it",
+                program,
+                timeout.as_millis()
+            );
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(AppError::Timeout {
+                command: program,
+                timeout,
+            }
+            .into());
+        }
+        std::thread::sleep(COMMAND_POLL_INTERVAL);
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(AppError::command_failed(&program, status, &stderr).into());
+    }
+    Ok(stdout)
+}
+
+/// Waits for the reply of a backend thread (`what` being its name in errors). A thread that
+/// hangs, like on a stalled compositor, mustn't take the controller down with it.
+fn wait_for_reply<T>(response: &Receiver<T>, what: &str, timeout: Duration) -> Result<T, Error> {
+    match response.recv_timeout(timeout) {
+        Ok(reply) => Ok(reply),
+        Err(RecvTimeoutError::Timeout) => {
+            warn!("{} didn't answer within {} ms", what, timeout.as_millis());
+            Err(AppError::Timeout {
+                command: what.to_string(),
+                timeout,
+            }
+            .into())
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(format_err!("{} is gone", what)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// `light` for the backlight
+    LightRedshift,
+    /// `/sys/class/backlight` for the backlight
+    Sysfs,
+    /// DDC/CI for the brightness of external monitors
+    Ddc,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "light-redshift" => Ok(BackendKind::LightRedshift),
+            "sysfs" => Ok(BackendKind::Sysfs),
+            "ddc" => Ok(BackendKind::Ddc),
+            _ => Err(format_err!("Unknown backend '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    /// Where sysfs is mounted, normally `/sys`
+    pub sysfs_root: PathBuf,
+    /// Backlight device name under `class/backlight`. The first one found is used if unset.
+    pub backlight_device: Option<String>,
+    /// I2C bus of the monitor to control, like `/dev/i2c-4`. If unset, every display connector
+    /// bus with a monitor answering DDC/CI is used.
+    pub ddc_device: Option<PathBuf>,
+    /// How gamma gets adjusted, for every backend
+    pub gamma: GammaKind,
+    pub light_command: String,
+    /// How long commands like `light` get before they are killed, and how long gamma sessions
+    /// get to answer
+    #[serde(
+        rename = "command_timeout_ms",
+        deserialize_with = "crate::config::deserialize_millis"
+    )]
+    pub command_timeout: Duration,
+    pub redshift_command: String,
+    /// Gamma adjustment method passed to `redshift -m`
+    pub redshift_method: String,
+    /// How often to check for backlight changes made by other programs. Zero disables it.
+    #[serde(
+        rename = "watch_interval_ms",
+        deserialize_with = "crate::config::deserialize_millis"
+    )]
+    pub watch_interval: Duration,
+    /// Whether to put the backlight back to the level it had at startup, and gamma back to
+    /// neutral, when the daemon exits
+    pub restore_on_exit: bool,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            kind: BackendKind::LightRedshift,
+            sysfs_root: "/sys".into(),
+            backlight_device: None,
+            ddc_device: None,
+            gamma: GammaKind::Auto,
+            light_command: "light".into(),
+            command_timeout: Duration::from_secs(3),
+            redshift_command: "redshift".into(),
+            redshift_method: "wayland".into(),
+            watch_interval: Duration::from_secs(2),
+            restore_on_exit: false,
+        }
+    }
+}
+
+pub fn create(config: &BackendConfig) -> Result<Box<dyn Backend>, Error> {
+    let redshift = Redshift::new(
+        &config.redshift_command,
+        &config.redshift_method,
+        config.command_timeout,
+    );
+    let gamma = gamma::create(config.gamma, redshift, config.command_timeout)?;
+    Ok(match config.kind {
+        BackendKind::LightRedshift => Box::new(LightRedshift::new(
+            &config.light_command,
+            config.command_timeout,
+            gamma,
+        )),
+        BackendKind::Sysfs => Box::new(Sysfs::new(
+            &config.sysfs_root,
+            config.backlight_device.as_deref(),
+            gamma,
+        )?),
+        BackendKind::Ddc => Box::new(Ddc::open(
+            config.ddc_device.as_deref(),
+            &config.sysfs_root,
+            gamma,
+        )?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_failures_can_be_told_apart() {
+        let timeout = Duration::from_secs(5);
+        let output = run(Command::new("sh").args(["-c", "echo 42"]), timeout).unwrap();
+        assert_eq!(output, b"42\n");
+
+        let err = run(
+            Command::new("sh").args(["-c", "echo oops >&2; exit 3"]),
+            timeout,
+        )
+        .unwrap_err();
+        assert_eq!(
+            AppError::from(err),
+            AppError::CommandFailed {
+                command: "sh".into(),
+                status: Some(3),
+                stderr: "oops".into(),
+            }
+        );
+
+        let err = run(&mut Command::new("/nonexistent/light"), timeout).unwrap_err();
+        match AppError::from(err) {
+            AppError::BackendUnavailable(message) => {
+                assert!(message.starts_with("Could not run /nonexistent/light"))
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn slow_commands_are_killed() {
+        let started = Instant::now();
+        let err = run(
+            Command::new("sh").args(["-c", "sleep 10"]),
+            Duration::from_millis(100),
+        )
+        .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            AppError::from(err),
+            AppError::Timeout {
+                command: "sh".into(),
+                timeout: Duration::from_millis(100),
+            }
+        );
+    }
+    #[test]
+    fn stuck_backend_threads_time_out() {
+        let (reply, response) = std::sync::mpsc::channel::<()>();
+        let err =
+            wait_for_reply(&response, "gamma session", Duration::from_millis(50)).unwrap_err();
+        assert_eq!(
+            AppError::from(err),
+            AppError::Timeout {
+                command: "gamma session".into(),
+                timeout: Duration::from_millis(50),
+            }
+        );
+
+        drop(reply);
+        let err =
+            wait_for_reply(&response, "gamma session", Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.to_string(), "gamma session is gone");
+    }
+}
@@ -0,0 +1,457 @@
+use super::gamma::Gamma;
+use super::{spawn_error, wait_for_reply};
+use failure::{format_err, Error};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the supervisor checks whether redshift is still running
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// Restart delays double after every failure, starting here and up to the maximum
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A process that has been running this long is no longer taken to be failing
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+/// redshift refuses to start with values outside these
+const MIN_BRIGHTNESS: f32 = 0.1;
+const MIN_TEMPERATURE: u32 = 1000;
+const MAX_TEMPERATURE: u32 = 25000;
+
+/// What the supervisor knows about the redshift process
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RedshiftStatus {
+    pub running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// Restarts after failures, since the daemon started
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+enum Message {
+    Set {
+        brightness: f32,
+        temperature: u32,
+        reply: Sender<Result<(), Error>>,
+    },
+    Shutdown,
+}
+
+/// Applies gamma by running `redshift` in one-shot mode. With some methods (like `wayland`) the
+/// process has to stay alive for the gamma ramps to stay applied, so it gets respawned on every
+/// change. Only used when a native gamma session isn't available.
+///
+/// The process is looked after by a supervisor thread, which logs its output and restarts it
+/// (waiting longer after every failure) when it exits with an error.
+pub struct Redshift {
+    method: String,
+    sender: Sender<Message>,
+    /// How long the supervisor gets to answer
+    timeout: Duration,
+    status: Arc<Mutex<RedshiftStatus>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Redshift {
+    pub fn new(command: &str, method: &str, timeout: Duration) -> Redshift {
+        let (sender, receiver) = channel();
+        let status = Arc::new(Mutex::new(RedshiftStatus::default()));
+        let supervisor = Supervisor {
+            command: command.into(),
+            method: method.into(),
+            process: None,
+            current: None,
+            failures: 0,
+            retry_at: None,
+            status: status.clone(),
+        };
+        let thread = std::thread::Builder::new()
+            .name("redshift".into())
+            .spawn(move || supervisor.run(receiver))
+            .expect("Could not start redshift supervisor thread");
+        Redshift {
+            method: method.into(),
+            sender,
+            timeout,
+            status,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+}
+
+impl Gamma for Redshift {
+    fn set(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        // Steep curves go below what redshift takes at the bottom of the scale, which would have
+        // it failing (and restarting) forever
+        let (reply, response) = channel();
+        self.sender
+            .send(Message::Set {
+                brightness: brightness.clamp(MIN_BRIGHTNESS, 1.0),
+                temperature: temperature.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE),
+                reply,
+            })
+            .map_err(|_| format_err!("redshift supervisor is gone"))?;
+        wait_for_reply(&response, "redshift supervisor", self.timeout)?
+    }
+
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        Some(self.status.lock().clone())
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.sender.send(Message::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A running redshift process, with the threads logging its output
+struct Process {
+    child: Child,
+    started: Instant,
+    stdout: JoinHandle<Option<String>>,
+    stderr: JoinHandle<Option<String>>,
+}
+
+impl Process {
+    /// Waits for the output to be logged, returning the last line redshift printed to stderr
+    fn finish(self) -> Option<String> {
+        let _ = self.stdout.join();
+        self.stderr.join().unwrap_or_default()
+    }
+}
+
+/// Logs every line from `pipe`, returning the last one
+fn log_lines(
+    pipe: Option<impl Read + Send + 'static>,
+    log: fn(&str),
+) -> JoinHandle<Option<String>> {
+    std::thread::spawn(move || {
+        let mut last = None;
+        if let Some(pipe) = pipe {
+            for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                if !line.trim().is_empty() {
+                    log(&line);
+                    last = Some(line);
+                }
+            }
+        }
+        last
+    })
+}
+
+struct Supervisor {
+    command: String,
+    method: String,
+    process: Option<Process>,
+    /// Brightness and temperature redshift was last started with, while they are applied (or
+    /// about to be, after a failure)
+    current: Option<(f32, u32)>,
+    /// Failures in a row, for the backoff
+    failures: u32,
+    /// When to start redshift again after a failure
+    retry_at: Option<Instant>,
+    status: Arc<Mutex<RedshiftStatus>>,
+}
+
+impl Supervisor {
+    fn run(mut self, receiver: Receiver<Message>) {
+        loop {
+            match receiver.recv_timeout(CHECK_INTERVAL) {
+                Ok(Message::Set {
+                    brightness,
+                    temperature,
+                    reply,
+                }) => {
+                    let _ = reply.send(self.set(brightness, temperature));
+                }
+                Ok(Message::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    self.kill();
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            self.check();
+        }
+    }
+
+    fn set(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        // Respawning redshift flickers, so skip it when nothing changed. While waiting to retry
+        // after a failure, new values are tried right away instead.
+        if self.retry_at.is_none() && self.current == Some((brightness, temperature)) {
+            return Ok(());
+        }
+        self.kill();
+        self.failures = 0;
+        self.current = Some((brightness, temperature));
+        self.spawn()
+    }
+
+    fn spawn(&mut self) -> Result<(), Error> {
+        self.retry_at = None;
+        let (brightness, temperature) = match self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        let spawned = Command::new(&self.command)
+            .arg("-m")
+            .arg(&self.method)
+            .arg("-O")
+            .arg(format!("{}", temperature))
+            .arg("-b")
+            .arg(format!("{}", brightness))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                let err = spawn_error(&self.command, err);
+                self.current = None;
+                let mut status = self.status.lock();
+                status.running = false;
+                status.pid = None;
+                status.last_error = Some(err.to_string());
+                return Err(err);
+            }
+        };
+
+        let mut status = self.status.lock();
+        status.running = true;
+        status.pid = Some(child.id());
+        self.process = Some(Process {
+            stdout: log_lines(child.stdout.take(), |line| info!("redshift: {}", line)),
+            stderr: log_lines(child.stderr.take(), |line| warn!("redshift: {}", line)),
+            child,
+            started: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Notices redshift exiting, and restarts it when it failed and its backoff is over
+    fn check(&mut self) {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() >= retry_at {
+                info!("Restarting redshift");
+                self.status.lock().restarts += 1;
+                if let Err(err) = self.spawn() {
+                    warn!("Could not restart redshift: {}", err);
+                }
+            }
+            return;
+        }
+
+        let process = match &mut self.process {
+            Some(process) => process,
+            None => return,
+        };
+        let exit_status = match process.child.try_wait() {
+            Ok(Some(exit_status)) => exit_status,
+            Ok(None) => {
+                if process.started.elapsed() >= STABLE_AFTER {
+                    self.failures = 0;
+                }
+                return;
+            }
+            Err(err) => {
+                warn!("Could not check on redshift: {}", err);
+                return;
+            }
+        };
+
+        let last_line = self.process.take().and_then(Process::finish);
+        let mut status = self.status.lock();
+        status.running = false;
+        status.pid = None;
+        // Methods like randr set the gamma ramps and exit
+        if exit_status.success() {
+            return;
+        }
+
+        let error = describe_failure(exit_status, last_line);
+        let backoff = backoff(self.failures);
+        self.failures += 1;
+        warn!("{}, restarting it in {} s", error, backoff.as_secs_f32());
+        status.last_error = Some(error);
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+
+    /// Stops redshift, if it's running
+    fn kill(&mut self) {
+        self.retry_at = None;
+        if let Some(mut process) = self.process.take() {
+            if let Err(err) = process.child.kill() {
+                warn!("Could not kill redshift process: {}", err);
+            }
+            if let Err(err) = process.child.wait() {
+                warn!("Could not wait on killed redshift process: {}", err);
+            }
+            process.finish();
+        }
+        let mut status = self.status.lock();
+        status.running = false;
+        status.pid = None;
+    }
+}
+
+fn describe_failure(exit_status: ExitStatus, last_line: Option<String>) -> String {
+    match last_line {
+        Some(line) => format!("redshift exited with {}: {}", exit_status, line),
+        None => format!("redshift exited with {}", exit_status),
+    }
+}
+
+/// How long to wait before restarting after `failures` failures in a row
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .checked_mul(1 << failures.min(16))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    /// Writes a script standing in for redshift
+    fn fake_redshift(dir: &std::path::Path, script: &str) -> String {
+        let path = dir.join("redshift");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn wait_for(
+        redshift: &Redshift,
+        condition: impl Fn(&RedshiftStatus) -> bool,
+    ) -> RedshiftStatus {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let status = redshift.redshift_status().unwrap();
+            if condition(&status) {
+                return status;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "gave up waiting, last saw {:?}",
+                status
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failures_are_reported_and_restarted() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = fake_redshift(
+            dir.path(),
+            "echo 'Wayland connection experienced a fatal error' >&2; exit 1",
+        );
+        let mut redshift = Redshift::new(&command, "wayland", TIMEOUT);
+        redshift.set(0.8, 4000).unwrap();
+
+        let status = wait_for(&redshift, |status| status.last_error.is_some());
+        assert!(!status.running);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some(
+                "redshift exited with exit status: 1: Wayland connection experienced a fatal error"
+            )
+        );
+
+        // The first retry comes after a second
+        let status = wait_for(&redshift, |status| status.restarts > 0);
+        assert_eq!(status.restarts, 1);
+        redshift.shutdown();
+    }
+
+    #[test]
+    fn one_shot_methods_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = fake_redshift(dir.path(), "echo 'Using method randr'");
+        let mut redshift = Redshift::new(&command, "randr", TIMEOUT);
+        redshift.set(1.0, 6500).unwrap();
+
+        wait_for(&redshift, |status| !status.running);
+        std::thread::sleep(CHECK_INTERVAL * 2);
+        assert_eq!(
+            redshift.redshift_status().unwrap(),
+            RedshiftStatus::default()
+        );
+        redshift.shutdown();
+    }
+
+    #[test]
+    fn long_running_processes_are_killed_on_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = fake_redshift(dir.path(), "exec sleep 60");
+        let mut redshift = Redshift::new(&command, "wayland", TIMEOUT);
+
+        redshift.set(1.0, 5000).unwrap();
+        let first = wait_for(&redshift, |status| status.running).pid;
+        redshift.set(1.0, 4000).unwrap();
+        let second = wait_for(&redshift, |status| status.running).pid;
+        assert_ne!(first, second);
+
+        redshift.shutdown();
+        let status = redshift.redshift_status().unwrap();
+        assert!(!status.running);
+        // Being killed by us isn't a failure
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.restarts, 0);
+    }
+
+    #[test]
+    fn values_are_kept_within_what_redshift_accepts() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = dir.path().join("args");
+        let command = fake_redshift(dir.path(), &format!("echo \"$@\" > {}", args.display()));
+        let mut redshift = Redshift::new(&command, "randr", TIMEOUT);
+
+        redshift.set(0.006, 500).unwrap();
+        wait_for(&redshift, |status| !status.running);
+        assert_eq!(
+            std::fs::read_to_string(&args).unwrap().trim(),
+            "-m randr -O 1000 -b 0.1"
+        );
+        redshift.shutdown();
+    }
+
+    #[test]
+    fn missing_binary_is_an_error() {
+        let mut redshift = Redshift::new("/nonexistent/redshift", "wayland", TIMEOUT);
+        assert!(redshift.set(1.0, 6500).is_err());
+        assert!(redshift
+            .redshift_status()
+            .unwrap()
+            .last_error
+            .unwrap()
+            .starts_with("Could not run /nonexistent/redshift"));
+        redshift.shutdown();
+    }
+}
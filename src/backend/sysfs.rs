@@ -0,0 +1,230 @@
+use super::gamma::Gamma;
+use super::{merge_outputs, Backend, OutputInfo, RedshiftStatus};
+use failure::{format_err, Error};
+use std::path::{Path, PathBuf};
+
+/// Drives the backlight by writing to `/sys/class/backlight/<device>/brightness` directly. The
+/// brightness file has to be writable by the user running sunset, which is usually arranged
+/// through a udev rule.
+pub struct Sysfs {
+    /// `<root>/class/backlight`, where every device lives
+    class_path: PathBuf,
+    device_path: PathBuf,
+    max_brightness: u32,
+    gamma: Box<dyn Gamma>,
+}
+
+pub(super) fn read_value(path: &Path) -> Result<u32, Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format_err!("Could not read {}: {}", path.display(), err))?;
+    contents
+        .trim()
+        .parse()
+        .map_err(|_| format_err!("Invalid value in {}: '{}'", path.display(), contents.trim()))
+}
+
+pub(super) fn list_devices(class_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut devices = std::fs::read_dir(class_path)
+        .map_err(|err| format_err!("Could not list {}: {}", class_path.display(), err))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    devices.sort();
+    Ok(devices)
+}
+
+pub(super) fn read_max_brightness(device_path: &Path) -> Result<u32, Error> {
+    let max_brightness = read_value(&device_path.join("max_brightness"))?;
+    if max_brightness == 0 {
+        return Err(format_err!(
+            "Backlight device {} reports a max_brightness of 0",
+            device_path.display()
+        ));
+    }
+    Ok(max_brightness)
+}
+
+fn write_percent(device_path: &Path, max_brightness: u32, percent: f32) -> Result<(), Error> {
+    let mut raw = (percent.clamp(0.0, 100.0) / 100.0 * max_brightness as f32).round() as u32;
+    // Coarse devices would round the lowest levels down to 0, which turns many panels off
+    if percent > 0.0 {
+        raw = raw.max(1);
+    }
+    let path = device_path.join("brightness");
+    std::fs::write(&path, format!("{}", raw))
+        .map_err(|err| format_err!("Could not write {}: {}", path.display(), err))
+}
+
+impl Sysfs {
+    /// Opens the given backlight device under `<root>/class/backlight`, or the first one found
+    /// there if no device is given.
+    pub fn new(root: &Path, device: Option<&str>, gamma: Box<dyn Gamma>) -> Result<Sysfs, Error> {
+        let class_path = root.join("class/backlight");
+
+        let device_path = match device {
+            Some(device) => class_path.join(device),
+            None => list_devices(&class_path)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    format_err!("No backlight devices found in {}", class_path.display())
+                })?,
+        };
+        let max_brightness = read_max_brightness(&device_path)?;
+
+        log::info!(
+            "Using backlight device {} (max brightness {})",
+            device_path.display(),
+            max_brightness
+        );
+
+        Ok(Sysfs {
+            class_path,
+            device_path,
+            max_brightness,
+            gamma,
+        })
+    }
+}
+
+impl Backend for Sysfs {
+    fn read_backlight(&mut self) -> Result<f32, Error> {
+        let raw = read_value(&self.device_path.join("brightness"))?;
+        Ok(raw as f32 * 100.0 / self.max_brightness as f32)
+    }
+
+    fn set_backlight(&mut self, percent: f32) -> Result<(), Error> {
+        write_percent(&self.device_path, self.max_brightness, percent)
+    }
+
+    fn set_gamma(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.gamma.set(brightness, temperature)
+    }
+
+    fn outputs(&mut self) -> Vec<OutputInfo> {
+        let backlights = list_devices(&self.class_path)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+            .collect();
+        merge_outputs(backlights, self.gamma.outputs())
+    }
+
+    fn set_output_backlight(&mut self, name: &str, percent: f32) -> Result<(), Error> {
+        if name.contains('/') || name.starts_with('.') {
+            return Err(format_err!("Invalid backlight device name '{}'", name));
+        }
+        let device_path = self.class_path.join(name);
+        let max_brightness = read_max_brightness(&device_path)?;
+        write_percent(&device_path, max_brightness, percent)
+    }
+
+    fn set_output_gamma(
+        &mut self,
+        name: &str,
+        brightness: f32,
+        temperature: u32,
+    ) -> Result<(), Error> {
+        self.gamma.set_output(name, brightness, temperature)
+    }
+
+    fn redshift_status(&self) -> Option<RedshiftStatus> {
+        self.gamma.redshift_status()
+    }
+
+    fn shutdown(&mut self) {
+        self.gamma.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(root: &Path, device: Option<&str>) -> Result<Sysfs, Error> {
+        let gamma = Box::new(crate::backend::Redshift::new(
+            "redshift",
+            "dummy",
+            std::time::Duration::from_secs(3),
+        ));
+        Sysfs::new(root, device, gamma)
+    }
+
+    fn fake_sysfs(devices: &[(&str, u32, u32)]) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        for (name, max, current) in devices {
+            let device = root.path().join("class/backlight").join(name);
+            std::fs::create_dir_all(&device).unwrap();
+            std::fs::write(device.join("max_brightness"), format!("{}\n", max)).unwrap();
+            std::fs::write(device.join("brightness"), format!("{}\n", current)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn reads_and_writes_percentages() {
+        let root = fake_sysfs(&[("intel_backlight", 1200, 300)]);
+        let mut backend = open(root.path(), Some("intel_backlight")).unwrap();
+
+        assert_eq!(backend.read_backlight().unwrap(), 25.0);
+
+        backend.set_backlight(50.0).unwrap();
+        let written = std::fs::read_to_string(
+            root.path()
+                .join("class/backlight/intel_backlight/brightness"),
+        )
+        .unwrap();
+        assert_eq!(written, "600");
+        assert_eq!(backend.read_backlight().unwrap(), 50.0);
+    }
+
+    #[test]
+    fn low_levels_keep_the_panel_on() {
+        let root = fake_sysfs(&[("amdgpu_bl0", 255, 100)]);
+        let mut backend = open(root.path(), None).unwrap();
+        let brightness = root.path().join("class/backlight/amdgpu_bl0/brightness");
+
+        backend.set_backlight(crate::MIN_BACKLIGHT).unwrap();
+        assert_eq!(std::fs::read_to_string(&brightness).unwrap(), "1");
+
+        backend.set_backlight(0.0).unwrap();
+        assert_eq!(std::fs::read_to_string(&brightness).unwrap(), "0");
+    }
+
+    #[test]
+    fn picks_first_device_when_none_given() {
+        let root = fake_sysfs(&[("b_device", 100, 10), ("a_device", 100, 90)]);
+        let mut backend = open(root.path(), None).unwrap();
+        assert_eq!(backend.read_backlight().unwrap(), 90.0);
+    }
+
+    #[test]
+    fn every_device_is_an_output() {
+        let root = fake_sysfs(&[("intel_backlight", 1200, 300), ("ddc_external", 100, 10)]);
+        let mut backend = open(root.path(), Some("intel_backlight")).unwrap();
+        let names: Vec<_> = backend
+            .outputs()
+            .into_iter()
+            .map(|output| output.name)
+            .collect();
+        assert_eq!(names, vec!["ddc_external", "intel_backlight"]);
+
+        backend.set_output_backlight("ddc_external", 40.0).unwrap();
+        let written =
+            std::fs::read_to_string(root.path().join("class/backlight/ddc_external/brightness"))
+                .unwrap();
+        assert_eq!(written, "40");
+        // The main device is left alone
+        assert_eq!(backend.read_backlight().unwrap(), 25.0);
+
+        assert!(backend
+            .set_output_backlight("../intel_backlight", 40.0)
+            .is_err());
+    }
+
+    #[test]
+    fn missing_device_is_an_error() {
+        let root = fake_sysfs(&[("intel_backlight", 100, 10)]);
+        assert!(open(root.path(), Some("acpi_video0")).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+// Native gamma control through the wlr-gamma-control Wayland protocol (sway and other
+// wlroots-based compositors).
+//
+// Gamma tables only stay applied while the client that set them stays connected, so the session
+// lives on its own thread for as long as the daemon runs, and receives new values over a
+// channel instead of being recreated on every change.
+
+use super::gamma::{ramps, Gamma};
+use super::wait_for_reply;
+use failure::{format_err, Error};
+use log::{info, warn};
+use std::cell::{Cell, RefCell};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use wayland_client::protocol::wl_output::{Event as OutputEvent, WlOutput};
+use wayland_client::{global_filter, Display, EventQueue, GlobalManager, Interface, Main};
+use wayland_protocols::wlr::unstable::gamma_control::v1::client::{
+    zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1,
+    zwlr_gamma_control_v1::{Event as ControlEvent, ZwlrGammaControlV1},
+};
+
+/// What the session thread is called in errors
+const SESSION: &str = "Wayland gamma session";
+
+enum Message {
+    Set {
+        /// Only this output, instead of all of them
+        output: Option<String>,
+        brightness: f32,
+        temperature: u32,
+        reply: Sender<Result<(), Error>>,
+    },
+    Outputs {
+        reply: Sender<Vec<String>>,
+    },
+    Shutdown,
+}
+
+pub struct WlrGamma {
+    sender: Sender<Message>,
+    /// How long the session gets to answer
+    timeout: Duration,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Checks whether the compositor supports the gamma control protocol, without taking over the
+/// gamma of any output. Returns how many outputs it has.
+pub fn probe() -> Result<usize, Error> {
+    let display = Display::connect_to_env()
+        .map_err(|err| format_err!("Could not connect to Wayland: {}", err))?;
+    let mut event_queue = display.create_event_queue();
+    let attached = display.attach(event_queue.token());
+    let globals = GlobalManager::new(&attached);
+    event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+    let globals = globals.list();
+    let has = |name| {
+        globals
+            .iter()
+            .filter(move |(_, interface, _)| interface == name)
+    };
+    if has(ZwlrGammaControlManagerV1::NAME).next().is_none() {
+        return Err(format_err!("Compositor doesn't support wlr-gamma-control"));
+    }
+    Ok(has(WlOutput::NAME).count())
+}
+
+impl WlrGamma {
+    /// Connects to the compositor, failing if it doesn't support the gamma control protocol
+    pub fn connect(timeout: Duration) -> Result<WlrGamma, Error> {
+        let (sender, receiver) = channel();
+        let (ready_sender, ready_receiver) = channel();
+
+        let thread = std::thread::Builder::new()
+            .name("wlr-gamma".into())
+            .spawn(move || match Session::connect() {
+                Ok(session) => {
+                    let _ = ready_sender.send(Ok(()));
+                    session.run(receiver);
+                }
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                }
+            })?;
+
+        wait_for_reply(&ready_receiver, SESSION, timeout)??;
+
+        Ok(WlrGamma {
+            sender,
+            timeout,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl WlrGamma {
+    fn send_set(
+        &mut self,
+        output: Option<String>,
+        brightness: f32,
+        temperature: u32,
+    ) -> Result<(), Error> {
+        let (reply, response) = channel();
+        self.sender
+            .send(Message::Set {
+                output,
+                brightness,
+                temperature,
+                reply,
+            })
+            .map_err(|_| format_err!("{} is gone", SESSION))?;
+        wait_for_reply(&response, SESSION, self.timeout)?
+    }
+}
+
+impl Gamma for WlrGamma {
+    fn set(&mut self, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.send_set(None, brightness, temperature)
+    }
+
+    fn outputs(&mut self) -> Vec<String> {
+        let (reply, response) = channel();
+        if self.sender.send(Message::Outputs { reply }).is_err() {
+            return Vec::new();
+        }
+        wait_for_reply(&response, SESSION, self.timeout).unwrap_or_default()
+    }
+
+    fn set_output(&mut self, name: &str, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.send_set(Some(name.to_string()), brightness, temperature)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.sender.send(Message::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Output {
+    output: Main<WlOutput>,
+    /// Connector name (like `eDP-1`), announced by compositors supporting wl_output version 4
+    name: Rc<RefCell<Option<String>>>,
+    control: Option<Main<ZwlrGammaControlV1>>,
+    /// Ramp size, as announced by the compositor
+    size: Rc<Cell<Option<u32>>>,
+    failed: Rc<Cell<bool>>,
+}
+
+impl Output {
+    /// Falls back to the position in the output list when the compositor doesn't announce names
+    fn name(&self, index: usize) -> String {
+        self.name
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| format!("wayland-{}", index))
+    }
+}
+
+struct Session {
+    event_queue: EventQueue,
+    manager: Main<ZwlrGammaControlManagerV1>,
+    outputs: Rc<RefCell<Vec<Output>>>,
+}
+
+impl Session {
+    fn connect() -> Result<Session, Error> {
+        let display = Display::connect_to_env()
+            .map_err(|err| format_err!("Could not connect to Wayland: {}", err))?;
+        let mut event_queue = display.create_event_queue();
+        let attached = display.attach(event_queue.token());
+
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let new_outputs = outputs.clone();
+        let globals = GlobalManager::new_with_cb(
+            &attached,
+            global_filter!([
+                WlOutput,
+                1,
+                move |output: Main<WlOutput>, _: DispatchData| {
+                    let name = Rc::new(RefCell::new(None));
+                    let announced_name = name.clone();
+                    output.quick_assign(move |_, event, _| {
+                        if let OutputEvent::Name { name } = event {
+                            *announced_name.borrow_mut() = Some(name);
+                        }
+                    });
+                    new_outputs.borrow_mut().push(Output {
+                        output,
+                        name,
+                        control: None,
+                        size: Rc::default(),
+                        failed: Rc::default(),
+                    })
+                }
+            ]),
+        );
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        let manager = globals
+            .instantiate_exact::<ZwlrGammaControlManagerV1>(1)
+            .map_err(|_| format_err!("Compositor doesn't support wlr-gamma-control"))?;
+
+        let mut session = Session {
+            event_queue,
+            manager,
+            outputs,
+        };
+        session.create_controls()?;
+        Ok(session)
+    }
+
+    /// Creates gamma controls for outputs that don't have one yet, and waits for their sizes
+    fn create_controls(&mut self) -> Result<(), Error> {
+        let mut created = false;
+        for output in self.outputs.borrow_mut().iter_mut() {
+            if output.control.is_some() {
+                continue;
+            }
+            let control = self.manager.get_gamma_control(&output.output);
+            let size = output.size.clone();
+            let failed = output.failed.clone();
+            control.quick_assign(move |_, event, _| match event {
+                ControlEvent::GammaSize { size: ramp_size } => size.set(Some(ramp_size)),
+                ControlEvent::Failed => failed.set(true),
+                _ => {}
+            });
+            output.control = Some(control);
+            created = true;
+        }
+        if created {
+            self.event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+        }
+        Ok(())
+    }
+
+    /// Picks up outputs plugged in since the last change
+    fn refresh(&mut self) -> Result<(), Error> {
+        self.event_queue.dispatch_pending(&mut (), |_, _, _| {})?;
+        self.create_controls()
+    }
+
+    fn output_names(&mut self) -> Vec<String> {
+        if let Err(err) = self.refresh() {
+            warn!("Could not refresh Wayland outputs: {}", err);
+        }
+        self.outputs
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(index, output)| output.name(index))
+            .collect()
+    }
+
+    fn set(&mut self, only: Option<&str>, brightness: f32, temperature: u32) -> Result<(), Error> {
+        self.refresh()?;
+
+        let mut applied = Vec::new();
+        for (index, output) in self.outputs.borrow().iter().enumerate() {
+            if only.is_some_and(|only| output.name(index) != only) {
+                continue;
+            }
+            let (control, size) = match (&output.control, output.size.get()) {
+                (Some(control), Some(size)) if !output.failed.get() => (control, size),
+                _ => continue,
+            };
+
+            let mut file = tempfile::tempfile()?;
+            let table: Vec<u8> = ramps(size as usize, brightness, temperature)
+                .iter()
+                .flat_map(|value| value.to_ne_bytes().to_vec())
+                .collect();
+            file.write_all(&table)?;
+            file.seek(SeekFrom::Start(0))?;
+
+            control.set_gamma(file.as_raw_fd());
+            applied.push((index, file));
+        }
+
+        // Make sure the compositor got the tables (and told us about failures) before the
+        // temporary files get closed
+        self.event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        let outputs = self.outputs.borrow();
+        let failed = applied
+            .iter()
+            .filter(|(index, _)| outputs[*index].failed.get())
+            .count();
+        if failed > 0 {
+            warn!("Gamma control failed on {} output(s)", failed);
+        }
+        if let (Some(only), true) = (only, applied.is_empty()) {
+            return Err(format_err!("No Wayland output called {}", only));
+        }
+        // Having no outputs at all (like with every screen off) is not an error
+        if !applied.is_empty() && failed == applied.len() {
+            return Err(format_err!("No output accepted gamma tables"));
+        }
+        Ok(())
+    }
+
+    fn run(mut self, receiver: Receiver<Message>) {
+        while let Ok(message) = receiver.recv() {
+            match message {
+                Message::Set {
+                    output,
+                    brightness,
+                    temperature,
+                    reply,
+                } => {
+                    let _ = reply.send(self.set(output.as_deref(), brightness, temperature));
+                }
+                Message::Outputs { reply } => {
+                    let _ = reply.send(self.output_names());
+                }
+                Message::Shutdown => break,
+            }
+        }
+
+        // Destroying the controls restores the original gamma tables
+        for output in self.outputs.borrow_mut().iter_mut() {
+            if let Some(control) = output.control.take() {
+                control.destroy();
+            }
+        }
+        self.manager.destroy();
+        let _ = self.event_queue.sync_roundtrip(&mut (), |_, _, _| {});
+        info!("Wayland gamma session closed");
+    }
+}
@@ -0,0 +1,29 @@
+use crate::config::Overrides;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(about = "Screen brightness and color temperature daemon")]
+pub struct Options {
+    #[structopt(flatten)]
+    pub overrides: Overrides,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Run the daemon (the default when no command is given)
+    Daemon,
+    /// Print the current brightness
+    Get,
+    /// Set the brightness
+    Set { brightness: f32 },
+    /// Increase the brightness by one step
+    Up,
+    /// Decrease the brightness by one step
+    Down,
+    /// Print brightness and color temperature
+    Status,
+    /// Check that the configured backend can work, and say what to fix if not
+    Doctor,
+}
@@ -0,0 +1,340 @@
+// Command line client for a running daemon. Keeps to plain blocking HTTP/1.1 over the daemon's
+// Unix socket (or TCP when there isn't one), since it only ever sends a couple of tiny requests.
+
+use crate::api::{Health, State};
+use crate::auth;
+use crate::backend::{self, CheckStatus};
+use crate::cli::Command;
+use crate::config::{Config, ServerConfig};
+use failure::{format_err, Error};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Exit codes for the client commands
+pub const EXIT_OK: i32 = 0;
+/// The daemon answered, but rejected the request (for `doctor`: something is broken)
+pub const EXIT_REQUEST_FAILED: i32 = 1;
+/// The daemon could not be reached
+pub const EXIT_UNREACHABLE: i32 = 2;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+enum ClientError {
+    Unreachable(Error),
+    RequestFailed(Error),
+}
+
+enum Transport {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+}
+
+struct Client {
+    transport: Transport,
+    token: Option<String>,
+}
+
+impl Client {
+    fn new(server: &ServerConfig, token: Option<String>) -> Client {
+        // The socket only exists while a daemon is listening on it (or after one crashed, in
+        // which case TCP wouldn't work either)
+        if let Some(path) = server.socket.as_ref().filter(|path| path.exists()) {
+            return Client {
+                transport: Transport::Unix(path.clone()),
+                token: None,
+            };
+        }
+
+        // A daemon listening on every interface is reachable through loopback
+        let host = match server.address.as_str() {
+            "0.0.0.0" => "127.0.0.1".to_string(),
+            "::" => "::1".to_string(),
+            address => address.to_string(),
+        };
+        Client {
+            transport: Transport::Tcp {
+                host,
+                port: server.port,
+            },
+            token,
+        }
+    }
+
+    /// Sends `request` and reads the whole response
+    fn exchange(&self, request: &str) -> Result<String, ClientError> {
+        let unreachable = |err| {
+            ClientError::Unreachable(match &self.transport {
+                Transport::Tcp { host, port } => format_err!(
+                    "Could not connect to the daemon at {}: {}",
+                    authority(host, *port),
+                    err
+                ),
+                Transport::Unix(path) => format_err!(
+                    "Could not connect to the daemon at {}: {}",
+                    path.display(),
+                    err
+                ),
+            })
+        };
+
+        let response = match &self.transport {
+            Transport::Tcp { host, port } => {
+                let stream = TcpStream::connect((host.as_str(), *port)).map_err(unreachable)?;
+                stream
+                    .set_read_timeout(Some(TIMEOUT))
+                    .map_err(unreachable)?;
+                stream
+                    .set_write_timeout(Some(TIMEOUT))
+                    .map_err(unreachable)?;
+                send(stream, request)
+            }
+            Transport::Unix(path) => {
+                let stream = UnixStream::connect(path).map_err(unreachable)?;
+                stream
+                    .set_read_timeout(Some(TIMEOUT))
+                    .map_err(unreachable)?;
+                stream
+                    .set_write_timeout(Some(TIMEOUT))
+                    .map_err(unreachable)?;
+                send(stream, request)
+            }
+        };
+        response.map_err(|err| {
+            ClientError::Unreachable(format_err!("Error talking to the daemon: {}", err))
+        })
+    }
+
+    /// Sends a GET request for `path`, returning the status and body of the response
+    fn fetch(&self, path: &str) -> Result<(u16, String), ClientError> {
+        let host = match &self.transport {
+            Transport::Tcp { host, port } => authority(host, *port),
+            Transport::Unix(_) => "localhost".to_string(),
+        };
+        let authorization = match &self.token {
+            Some(token) => format!("Authorization: Bearer {}\r\n", token),
+            None => String::new(),
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+            path, host, authorization
+        );
+        let response = self.exchange(&request)?;
+
+        let (head, body) = match response.find("\r\n\r\n") {
+            Some(index) => (&response[..index], &response[index + 4..]),
+            None => (response.as_str(), ""),
+        };
+        let status: u16 = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| {
+                ClientError::RequestFailed(format_err!("Invalid response from the daemon"))
+            })?;
+        Ok((status, body.trim().to_string()))
+    }
+
+    /// Sends a GET request for `path`, returning the response body
+    fn get(&self, path: &str) -> Result<String, ClientError> {
+        let (status, body) = self.fetch(path)?;
+        if !(200..300).contains(&status) {
+            // Errors come as JSON with a readable message in `error`
+            let message = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|error| error["error"].as_str().map(str::to_string))
+                .unwrap_or_else(|| body.trim().to_string());
+            return Err(ClientError::RequestFailed(if message.is_empty() {
+                format_err!("Daemon returned HTTP {}", status)
+            } else {
+                format_err!("Daemon returned HTTP {}: {}", status, message)
+            }));
+        }
+
+        Ok(body)
+    }
+}
+
+/// `host:port`, with IPv6 addresses in brackets as URLs and `Host` headers need them
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn send(mut stream: impl Read + Write, request: &str) -> std::io::Result<String> {
+    let mut response = String::new();
+    stream.write_all(request.as_bytes())?;
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+fn execute(client: &Client, command: &Command) -> Result<(), ClientError> {
+    match command {
+        Command::Daemon | Command::Doctor => unreachable!("not a client command"),
+        Command::Get => println!("{}", client.get("/get")?),
+        // These answer with the brightness being moved to, which `/get` only reports once a
+        // transition is over
+        Command::Set { brightness } => {
+            println!(
+                "{}",
+                client.get(&format!("/set?brightness={}", brightness))?
+            )
+        }
+        Command::Up => println!("{}", client.get("/brighter")?),
+        Command::Down => println!("{}", client.get("/darker")?),
+        Command::Status => {
+            let state: State =
+                serde_json::from_str(&client.get("/api/v1/state")?).map_err(|err| {
+                    ClientError::RequestFailed(format_err!(
+                        "Invalid state from the daemon: {}",
+                        err
+                    ))
+                })?;
+            println!("Brightness:  {}", state.brightness);
+            println!("Backlight:   {:.1}%", state.backlight);
+            println!("Gamma:       {:.2}", state.gamma);
+            println!("Temperature: {}K", state.temperature);
+            println!(
+                "Mode:        {}",
+                if state.manual_override {
+                    "manual"
+                } else {
+                    "automatic"
+                }
+            );
+            match state.backend.error {
+                None => println!("Backend:     ok"),
+                Some(err) => println!("Backend:     error: {}", err),
+            }
+            if let Some(redshift) = state.redshift {
+                match redshift.pid {
+                    Some(pid) => print!("Redshift:    running (pid {})", pid),
+                    None => print!("Redshift:    not running"),
+                }
+                match redshift.restarts {
+                    0 => println!(),
+                    1 => println!(", restarted once"),
+                    n => println!(", restarted {} times", n),
+                }
+                if let Some(err) = redshift.last_error {
+                    println!("             last error: {}", err);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a client command against the daemon, returning the process exit code
+pub fn run(command: &Command, server: &ServerConfig) -> i32 {
+    let token = match auth::client_token(server) {
+        Ok(token) => token,
+        Err(err) => {
+            eprintln!("Could not read the API token: {}", err);
+            return EXIT_REQUEST_FAILED;
+        }
+    };
+    match execute(&Client::new(server, token), command) {
+        Ok(()) => EXIT_OK,
+        Err(ClientError::Unreachable(err)) => {
+            eprintln!("{}", err);
+            EXIT_UNREACHABLE
+        }
+        Err(ClientError::RequestFailed(err)) => {
+            eprintln!("{}", err);
+            EXIT_REQUEST_FAILED
+        }
+    }
+}
+
+/// Gets the daemon's health report, which is unhealthy (and still a report) on HTTP 503
+fn fetch_health(client: &Client) -> Result<Health, ClientError> {
+    let (status, body) = client.fetch("/health")?;
+    if status != 200 && status != 503 {
+        return Err(ClientError::RequestFailed(format_err!(
+            "Daemon returned HTTP {}",
+            status
+        )));
+    }
+    serde_json::from_str(&body).map_err(|err| {
+        ClientError::RequestFailed(format_err!(
+            "Invalid health report from the daemon: {}",
+            err
+        ))
+    })
+}
+
+/// Prints what works and what doesn't, with hints for fixing it, returning the process exit
+/// code. A running daemon does the checks itself, since it's the one whose environment matters;
+/// otherwise they are done here.
+pub fn doctor(config: &Config) -> i32 {
+    let token = auth::client_token(&config.server).unwrap_or(None);
+    let health = match fetch_health(&Client::new(&config.server, token)) {
+        Ok(health) => {
+            println!("Checked by the running daemon:");
+            health
+        }
+        Err(ClientError::Unreachable(_)) => {
+            println!("No daemon running, checking from here:");
+            Health::new(backend::diagnose(&config.backend))
+        }
+        Err(ClientError::RequestFailed(err)) => {
+            println!("{}, checking from here:", err);
+            Health::new(backend::diagnose(&config.backend))
+        }
+    };
+
+    for check in &health.checks {
+        let status = match check.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Error => "error",
+        };
+        println!(
+            "{:>9}  {}: {}",
+            format!("[{}]", status),
+            check.name,
+            check.detail
+        );
+        if let Some(hint) = &check.hint {
+            println!("{:>9}  -> {}", "", hint);
+        }
+    }
+
+    let problems = health
+        .checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Error)
+        .count();
+    match problems {
+        0 => {
+            println!("Everything needed is in place");
+            EXIT_OK
+        }
+        1 => {
+            println!("Found 1 problem");
+            EXIT_REQUEST_FAILED
+        }
+        n => {
+            println!("Found {} problems", n);
+            EXIT_REQUEST_FAILED
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_hosts_are_bracketed() {
+        assert_eq!(authority("127.0.0.1", 12321), "127.0.0.1:12321");
+        assert_eq!(authority("localhost", 80), "localhost:80");
+        assert_eq!(authority("::1", 12321), "[::1]:12321");
+    }
+}
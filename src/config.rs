@@ -0,0 +1,538 @@
+// Daemon configuration, loaded from `~/.config/sunset/config.toml`. Every setting has a default,
+// so the file is optional, and the most common settings can be overridden from the command line.
+
+use crate::backend::{BackendConfig, BackendKind};
+use crate::curve::Curve;
+use crate::solar::Location;
+use failure::{format_err, Error};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Loopback only by default: anything else lets other machines on the network in
+    pub address: String,
+    pub port: u16,
+    /// Whether to listen on `address` and `port` at all
+    pub tcp: bool,
+    /// Unix socket to listen on as well, defaulting to `$XDG_RUNTIME_DIR/sunset.sock`. Only its
+    /// owner can connect, so it doesn't need a token.
+    pub socket: Option<PathBuf>,
+    /// Whether to offer `org.sunset.Brightness` on the D-Bus session bus
+    pub dbus: bool,
+    /// Whether requests need an `Authorization: Bearer` token
+    pub auth: bool,
+    /// Token to require. If unset, it's read from `token_file` (generated if missing).
+    pub token: Option<String>,
+    pub token_file: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "127.0.0.1".into(),
+            port: 12321,
+            tcp: true,
+            socket: dirs::runtime_dir().map(|dir| dir.join("sunset.sock")),
+            dbus: true,
+            auth: true,
+            token: None,
+            token_file: dirs::config_dir().unwrap_or_default().join("sunset/token"),
+        }
+    }
+}
+
+/// Brightness is a single scale: below `pivot` the screen is dimmed through gamma, above it the
+/// backlight goes from 0% (at `pivot`) to 100% (at `max`).
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrightnessConfig {
+    pub min: f32,
+    pub max: f32,
+    pub pivot: f32,
+    /// Amount /brighter and /darker change the brightness by
+    pub step: f32,
+    /// How positions on each side of the pivot map to gamma and backlight levels
+    pub curve: Curve,
+}
+
+impl Default for BrightnessConfig {
+    fn default() -> Self {
+        BrightnessConfig {
+            min: 10.0,
+            max: 200.0,
+            pivot: 100.0,
+            step: 5.0,
+            curve: Curve::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemperatureConfig {
+    /// Range the color temperature is clamped to, in Kelvin
+    pub min: u32,
+    pub max: u32,
+    /// Amount /warmer and /cooler change the temperature by
+    pub step: u32,
+}
+
+impl Default for TemperatureConfig {
+    fn default() -> Self {
+        TemperatureConfig {
+            min: 1000,
+            max: 6500,
+            step: 250,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransitionConfig {
+    /// How long brightness changes take when a request doesn't say otherwise. Zero means changes
+    /// are applied immediately.
+    #[serde(rename = "duration_ms", deserialize_with = "deserialize_millis")]
+    pub duration: Duration,
+    /// How many intermediate steps per second a transition goes through
+    pub frame_rate: u32,
+}
+
+impl Default for TransitionConfig {
+    fn default() -> Self {
+        TransitionConfig {
+            duration: Duration::from_millis(0),
+            frame_rate: 30,
+        }
+    }
+}
+
+pub fn deserialize_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+}
+
+fn default_day_brightness() -> f32 {
+    200.0
+}
+
+fn default_day_temperature() -> u32 {
+    6500
+}
+
+fn default_night_brightness() -> f32 {
+    100.0
+}
+
+fn default_night_temperature() -> u32 {
+    3500
+}
+
+fn default_update_interval() -> u64 {
+    30
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Brightness and color temperature used while the sun is up
+    #[serde(default = "default_day_brightness")]
+    pub day_brightness: f32,
+    #[serde(default = "default_day_temperature")]
+    pub day_temperature: u32,
+    /// Brightness and color temperature used after civil twilight
+    #[serde(default = "default_night_brightness")]
+    pub night_brightness: f32,
+    #[serde(default = "default_night_temperature")]
+    pub night_temperature: u32,
+    /// How often the scheduler re-evaluates the sun position, in seconds
+    #[serde(default = "default_update_interval")]
+    pub update_interval: u64,
+}
+
+impl ScheduleConfig {
+    pub fn location(&self) -> Location {
+        Location {
+            latitude: self.latitude,
+            longitude: self.longitude,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub brightness: BrightnessConfig,
+    pub temperature: TemperatureConfig,
+    pub transition: TransitionConfig,
+    pub backend: BackendConfig,
+    /// The solar scheduler only runs when a location has been configured
+    pub schedule: Option<ScheduleConfig>,
+    /// Where brightness and temperature are saved across restarts
+    pub state_file: Option<PathBuf>,
+    /// Names for sets of outputs that get adjusted together, like `laptop = ["intel_backlight",
+    /// "eDP-1"]` to pair a backlight device with the gamma of the same screen
+    pub output_groups: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::default(),
+            brightness: BrightnessConfig::default(),
+            temperature: TemperatureConfig::default(),
+            transition: TransitionConfig::default(),
+            backend: BackendConfig::default(),
+            schedule: None,
+            state_file: crate::state::default_path(),
+            output_groups: BTreeMap::new(),
+        }
+    }
+}
+
+/// Settings that can be overridden from the command line
+#[derive(StructOpt, Default)]
+pub struct Overrides {
+    /// Configuration file [default: ~/.config/sunset/config.toml]
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[structopt(long)]
+    pub address: Option<String>,
+    /// Port to listen on
+    #[structopt(long)]
+    pub port: Option<u16>,
+    /// Unix socket to listen on
+    #[structopt(long, parse(from_os_str))]
+    pub socket: Option<PathBuf>,
+    /// Display backend: light-redshift, sysfs or ddc
+    #[structopt(long)]
+    pub backend: Option<BackendKind>,
+    /// Backlight device under /sys/class/backlight
+    #[structopt(long)]
+    pub backlight_device: Option<String>,
+    /// Brightness change for /brighter and /darker
+    #[structopt(long)]
+    pub step: Option<f32>,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("sunset/config.toml"))
+}
+
+impl Config {
+    /// Loads the configuration file (if any), applies the command line overrides and validates
+    /// the result
+    pub fn load(overrides: &Overrides) -> Result<Config, Error> {
+        let mut config = match &overrides.config {
+            Some(path) => Config::from_file(path)?,
+            None => match default_path() {
+                Some(path) if path.exists() => Config::from_file(&path)?,
+                _ => Config::default(),
+            },
+        };
+
+        if let Some(address) = &overrides.address {
+            config.server.address = address.clone();
+        }
+        if let Some(port) = overrides.port {
+            config.server.port = port;
+        }
+        if let Some(socket) = &overrides.socket {
+            config.server.socket = Some(socket.clone());
+        }
+        if let Some(backend) = overrides.backend {
+            config.backend.kind = backend;
+        }
+        if let Some(device) = &overrides.backlight_device {
+            config.backend.backlight_device = Some(device.clone());
+        }
+        if let Some(step) = overrides.step {
+            config.brightness.step = step;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format_err!("Could not read {}: {}", path.display(), err))?;
+        Config::parse(&contents).map_err(|err| format_err!("{}: {}", path.display(), err))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, Error> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let brightness = &self.brightness;
+        if !(brightness.min > 0.0
+            && brightness.min <= brightness.pivot
+            && brightness.pivot < brightness.max)
+        {
+            return Err(format_err!(
+                "brightness limits must satisfy 0 < min <= pivot < max (got min = {}, pivot = {}, max = {})",
+                brightness.min,
+                brightness.pivot,
+                brightness.max
+            ));
+        }
+        if !brightness.step.is_finite() || brightness.step <= 0.0 {
+            return Err(format_err!(
+                "brightness.step must be positive (got {})",
+                brightness.step
+            ));
+        }
+        brightness.curve.validate()?;
+
+        let temperature = &self.temperature;
+        // redshift refuses temperatures outside 1000 to 25000 K
+        if temperature.min < 1000 || temperature.min > temperature.max || temperature.max > 25000 {
+            return Err(format_err!(
+                "temperature limits must satisfy 1000 <= min <= max <= 25000 (got min = {}, max = {})",
+                temperature.min,
+                temperature.max
+            ));
+        }
+
+        if self.transition.frame_rate == 0 || self.transition.frame_rate > 240 {
+            return Err(format_err!(
+                "transition.frame_rate must be between 1 and 240 (got {})",
+                self.transition.frame_rate
+            ));
+        }
+
+        if let Some(schedule) = &self.schedule {
+            if !(-90.0..=90.0).contains(&schedule.latitude) {
+                return Err(format_err!(
+                    "schedule.latitude must be between -90 and 90 (got {})",
+                    schedule.latitude
+                ));
+            }
+            if !(-180.0..=180.0).contains(&schedule.longitude) {
+                return Err(format_err!(
+                    "schedule.longitude must be between -180 and 180 (got {})",
+                    schedule.longitude
+                ));
+            }
+            for (name, value) in [
+                ("day_brightness", schedule.day_brightness),
+                ("night_brightness", schedule.night_brightness),
+            ] {
+                if !(brightness.min..=brightness.max).contains(&value) {
+                    return Err(format_err!(
+                        "schedule.{} must be between brightness.min and brightness.max ({} to {}, got {})",
+                        name,
+                        brightness.min,
+                        brightness.max,
+                        value
+                    ));
+                }
+            }
+            for (name, value) in [
+                ("day_temperature", schedule.day_temperature),
+                ("night_temperature", schedule.night_temperature),
+            ] {
+                if !(temperature.min..=temperature.max).contains(&value) {
+                    return Err(format_err!(
+                        "schedule.{} must be between temperature.min and temperature.max ({} to {}, got {})",
+                        name,
+                        temperature.min,
+                        temperature.max,
+                        value
+                    ));
+                }
+            }
+            if schedule.update_interval == 0 {
+                return Err(format_err!(
+                    "schedule.update_interval must be at least 1 second"
+                ));
+            }
+        }
+
+        if !self.server.tcp && self.server.socket.is_none() {
+            return Err(format_err!(
+                "server.tcp is disabled and there is no server.socket, so there is nothing to listen on"
+            ));
+        }
+
+        if let Some(token) = &self.server.token {
+            if token.trim().is_empty() {
+                return Err(format_err!(
+                    "server.token can't be empty (remove it to use the token file instead)"
+                ));
+            }
+        }
+
+        for (group, members) in &self.output_groups {
+            if members.is_empty() {
+                return Err(format_err!("output group {} has no outputs", group));
+            }
+        }
+
+        if self.backend.command_timeout.is_zero() {
+            return Err(format_err!("backend.command_timeout_ms must be positive"));
+        }
+
+        if self.backend.redshift_method.is_empty() {
+            return Err(format_err!("backend.redshift_method can't be empty"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::GammaKind;
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.server.address, "127.0.0.1");
+        assert_eq!(config.server.port, 12321);
+        assert!(config.server.auth);
+        assert!(config.server.tcp);
+        assert_eq!(config.brightness.step, 5.0);
+        assert_eq!(config.brightness.curve, Curve::Linear);
+        assert_eq!(config.backend.kind, BackendKind::LightRedshift);
+        assert_eq!(config.backend.redshift_method, "wayland");
+        assert!(config.schedule.is_none());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn parses_all_sections() {
+        let config = Config::parse(
+            r#"
+            state_file = "/tmp/sunset-state.json"
+
+            [server]
+            address = "127.0.0.1"
+            port = 4000
+
+            [brightness]
+            min = 20
+            max = 150
+            pivot = 50
+            step = 2.5
+
+            [brightness.curve]
+            kind = "points"
+            points = [[0, 0], [0.5, 0.2], [1, 1]]
+
+            [temperature]
+            min = 2000
+            max = 6500
+            step = 100
+
+            [backend]
+            kind = "sysfs"
+            backlight_device = "intel_backlight"
+            gamma = "redshift"
+            redshift_method = "randr"
+            watch_interval_ms = 500
+            command_timeout_ms = 1500
+            restore_on_exit = true
+
+            [output_groups]
+            laptop = ["intel_backlight", "eDP-1"]
+
+            [schedule]
+            latitude = 40.4
+            longitude = -3.7
+            day_brightness = 150
+            night_temperature = 3000
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.server.address, "127.0.0.1");
+        assert_eq!(config.server.port, 4000);
+        assert_eq!(config.brightness.pivot, 50.0);
+        assert_eq!(config.brightness.step, 2.5);
+        assert_eq!(
+            config.brightness.curve,
+            Curve::Points {
+                points: vec![[0.0, 0.0], [0.5, 0.2], [1.0, 1.0]]
+            }
+        );
+        assert_eq!(config.temperature.step, 100);
+        assert_eq!(config.backend.kind, BackendKind::Sysfs);
+        assert_eq!(
+            config.backend.backlight_device.as_deref(),
+            Some("intel_backlight")
+        );
+        assert_eq!(config.backend.gamma, GammaKind::Redshift);
+        assert_eq!(config.backend.light_command, "light");
+        assert_eq!(config.backend.watch_interval, Duration::from_millis(500));
+        assert_eq!(config.backend.command_timeout, Duration::from_millis(1500));
+        assert!(config.backend.restore_on_exit);
+
+        assert_eq!(
+            config.output_groups["laptop"],
+            vec!["intel_backlight", "eDP-1"]
+        );
+
+        let schedule = config.schedule.unwrap();
+        assert_eq!(schedule.night_temperature, 3000);
+        assert_eq!(schedule.day_temperature, 6500);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::parse("[server]\nprot = 80").is_err());
+        assert!(Config::parse("[backend]\nkind = \"xrandr\"").is_err());
+        assert!(Config::parse("[backend]\nkind = \"ddc\"").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        let config = Config::parse("[brightness]\npivot = 250").unwrap();
+        assert!(config.validate().is_err());
+
+        let config = Config::parse("[brightness.curve]\nkind = \"power\"\nexponent = -1").unwrap();
+        assert!(config.validate().is_err());
+
+        let config = Config::parse("[backend]\ncommand_timeout_ms = 0").unwrap();
+        assert!(config.validate().is_err());
+
+        let config = Config::parse("[temperature]\nmin = 7000\nmax = 3000").unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::parse("[temperature]\nmin = 500").unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::parse("[temperature]\nmax = 30000").unwrap();
+        assert!(config.validate().is_err());
+
+        let config = Config::parse("[schedule]\nlatitude = 100\nlongitude = 0").unwrap();
+        assert!(config.validate().is_err());
+
+        let mut config = Config::parse("").unwrap();
+        config.brightness.step = f32::NAN;
+        assert!(config.validate().is_err());
+
+        let schedule = "[schedule]\nlatitude = 52\nlongitude = 13\n";
+        let config = Config::parse(&format!("{}night_brightness = 0", schedule)).unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::parse(&format!("{}day_temperature = 9000", schedule)).unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::parse(schedule).unwrap();
+        assert!(config.validate().is_ok());
+
+        let mut config = Config::parse("[server]\ntcp = false").unwrap();
+        config.server.socket = None;
+        assert!(config.validate().is_err());
+    }
+}
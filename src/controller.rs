@@ -0,0 +1,104 @@
+// Serializes every change to the daemon state.
+//
+// A single thread owns AppData and runs the jobs sent to it one at a time, so a change and the
+// backend calls applying it can't interleave with another request. Web handlers get the result
+// as a future instead of blocking a worker while the backend works; other threads (scheduler,
+// watcher, D-Bus) simply wait for it. Transition frames run from the same loop, between jobs.
+
+use crate::error::AppError;
+use crate::{transition, AppData};
+use futures::sync::oneshot;
+use futures::Future;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Instant;
+
+/// Work for the controller thread. Returns whether to keep going afterwards.
+type Job = Box<dyn FnOnce(&mut AppData) -> bool + Send>;
+
+/// Handle for sending jobs to the controller thread
+#[derive(Clone)]
+pub struct Controller {
+    jobs: Sender<Job>,
+}
+
+impl Controller {
+    /// Starts the controller thread, which owns `data` from then on
+    pub fn spawn(data: AppData) -> Controller {
+        let (jobs, receiver) = channel();
+        std::thread::Builder::new()
+            .name("controller".into())
+            .spawn(move || run(data, receiver))
+            .expect("Could not start controller thread");
+        Controller { jobs }
+    }
+
+    fn send<R, F>(&self, keep_going: bool, f: F) -> impl Future<Item = R, Error = AppError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppData) -> Result<R, AppError> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |data| {
+            let _ = reply.send(f(data));
+            keep_going
+        });
+        // Once the controller stops, the job (and the reply sender in it) gets dropped, which
+        // cancels `result`
+        let _ = self.jobs.send(job);
+        result.then(|result| match result {
+            Ok(result) => result,
+            Err(oneshot::Canceled) => Err(AppError::ShuttingDown),
+        })
+    }
+
+    /// Runs `f` on the controller thread, resolving to its result
+    pub fn call<R, F>(&self, f: F) -> impl Future<Item = R, Error = AppError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppData) -> Result<R, AppError> + Send + 'static,
+    {
+        self.send(true, f)
+    }
+
+    /// Runs `f` on the controller thread and waits for it. Not for web handlers, which would
+    /// block a worker.
+    pub fn run<R, F>(&self, f: F) -> Result<R, AppError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppData) -> Result<R, AppError> + Send + 'static,
+    {
+        self.send(true, f).wait()
+    }
+
+    /// Runs `f` as the last job and waits for it. Anything sent afterwards fails with
+    /// `AppError::ShuttingDown`.
+    pub fn stop<R, F>(&self, f: F) -> Result<R, AppError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppData) -> Result<R, AppError> + Send + 'static,
+    {
+        self.send(false, f).wait()
+    }
+}
+
+fn run(mut data: AppData, jobs: Receiver<Job>) {
+    loop {
+        let job = match transition::next_frame(&data) {
+            Some(at) => match jobs.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) => {
+                    transition::step(&mut data);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match jobs.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            },
+        };
+        if !job(&mut data) {
+            return;
+        }
+    }
+}
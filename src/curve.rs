@@ -0,0 +1,227 @@
+// Brightness curves: how a position on the brightness scale turns into backlight and gamma
+// levels.
+//
+// Both halves of the scale are mapped the same way. Below the pivot, the position goes from 0
+// (brightness 0) to 1 (the pivot) and the curve gives the gamma factor. Above it, the position
+// goes from 0 (the pivot) to 1 (the maximum) and the curve gives the backlight level. Every curve
+// goes through (0, 0) and (1, 1), so the two halves meet at the pivot.
+
+use failure::{format_err, Error};
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Curve {
+    /// Output proportional to the position
+    #[default]
+    Linear,
+    /// Output is the position raised to `exponent`. Above 1 gives finer steps at low levels.
+    Power { exponent: f64 },
+    /// Every step multiplies the output by the same factor, which is how eyes perceive light.
+    /// `base` is the ratio between the highest and lowest levels.
+    Logarithmic { base: f64 },
+    /// Straight lines between control points, as `[position, output]` pairs going from
+    /// `[0, 0]` to `[1, 1]`
+    Points { points: Vec<[f64; 2]> },
+}
+
+/// Interpolates `x` between two points, where the first coordinate of each is the input
+fn lerp(x: f64, from: [f64; 2], to: [f64; 2]) -> f64 {
+    from[1] + (x - from[0]) * (to[1] - from[1]) / (to[0] - from[0])
+}
+
+/// Interpolates along a polyline, `points` having increasing first coordinates
+fn piecewise(x: f64, points: impl Iterator<Item = [f64; 2]> + Clone) -> f64 {
+    let segments = points.clone().zip(points.skip(1));
+    for (from, to) in segments {
+        if x <= to[0] {
+            return lerp(x, from, to);
+        }
+    }
+    1.0
+}
+
+impl Curve {
+    /// Output level (0.0 to 1.0) for a position (0.0 to 1.0) on one half of the scale
+    pub fn apply(&self, position: f64) -> f64 {
+        let position = position.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => position,
+            Curve::Power { exponent } => position.powf(*exponent),
+            Curve::Logarithmic { base } => (base.powf(position) - 1.0) / (base - 1.0),
+            Curve::Points { points } => piecewise(position, points.iter().copied()),
+        }
+    }
+
+    /// Position that gives an output level, the inverse of `apply`
+    pub fn position(&self, output: f64) -> f64 {
+        let output = output.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => output,
+            Curve::Power { exponent } => output.powf(1.0 / exponent),
+            Curve::Logarithmic { base } => (1.0 + output * (base - 1.0)).ln() / base.ln(),
+            Curve::Points { points } => piecewise(
+                output,
+                points.iter().map(|&[position, output]| [output, position]),
+            ),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Curve::Linear => {}
+            Curve::Power { exponent } => {
+                if !(*exponent > 0.0 && exponent.is_finite()) {
+                    return Err(format_err!(
+                        "power curve exponent must be positive (got {})",
+                        exponent
+                    ));
+                }
+            }
+            Curve::Logarithmic { base } => {
+                if !(*base > 1.0 && base.is_finite()) {
+                    return Err(format_err!(
+                        "logarithmic curve base must be greater than 1 (got {})",
+                        base
+                    ));
+                }
+            }
+            Curve::Points { points } => {
+                if points.first() != Some(&[0.0, 0.0]) || points.last() != Some(&[1.0, 1.0]) {
+                    return Err(format_err!(
+                        "curve points must start at [0, 0] and end at [1, 1]"
+                    ));
+                }
+                // Strictly increasing both ways, so every level has exactly one position
+                if points
+                    .windows(2)
+                    .any(|pair| pair[1][0] <= pair[0][0] || pair[1][1] <= pair[0][1])
+                {
+                    return Err(format_err!(
+                        "curve points must be increasing in both position and output"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrightnessConfig;
+    use crate::Brightness;
+
+    fn curves() -> Vec<Curve> {
+        vec![
+            Curve::Linear,
+            Curve::Power { exponent: 2.2 },
+            Curve::Power { exponent: 0.5 },
+            Curve::Logarithmic { base: 100.0 },
+            Curve::Points {
+                points: vec![[0.0, 0.0], [0.5, 0.1], [0.8, 0.4], [1.0, 1.0]],
+            },
+        ]
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    fn roughly(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn curves_are_monotonic_and_go_from_zero_to_one() {
+        for curve in curves() {
+            curve.validate().unwrap();
+            assert!(close(curve.apply(0.0), 0.0), "{:?}", curve);
+            assert!(close(curve.apply(1.0), 1.0), "{:?}", curve);
+
+            let outputs: Vec<f64> = (0..=1000).map(|i| curve.apply(i as f64 / 1000.0)).collect();
+            assert!(
+                outputs.windows(2).all(|pair| pair[1] >= pair[0]),
+                "{:?} is not monotonic",
+                curve
+            );
+        }
+    }
+
+    #[test]
+    fn position_inverts_apply() {
+        for curve in curves() {
+            for i in 0..=20 {
+                let position = i as f64 / 20.0;
+                let round_trip = curve.position(curve.apply(position));
+                assert!(close(round_trip, position), "{:?} at {}", curve, position);
+            }
+        }
+    }
+
+    #[test]
+    fn brightness_is_continuous_at_the_pivot() {
+        for curve in curves() {
+            let limits = BrightnessConfig {
+                curve: curve.clone(),
+                ..BrightnessConfig::default()
+            };
+            let at = |value: f32| Brightness {
+                value,
+                limits: limits.clone(),
+            };
+
+            let below = at(limits.pivot - 0.0001);
+            let pivot = at(limits.pivot);
+            let above = at(limits.pivot + 0.0001);
+            assert!(roughly(below.to_redshift(), 1.0), "{:?}", curve);
+            assert_eq!(pivot.to_redshift(), 1.0);
+            assert_eq!(above.to_redshift(), 1.0);
+            assert!(roughly(below.to_light(), pivot.to_light()), "{:?}", curve);
+            assert!(roughly(above.to_light(), pivot.to_light()), "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn brightness_is_monotonic_across_the_scale() {
+        for curve in curves() {
+            let limits = BrightnessConfig {
+                curve: curve.clone(),
+                ..BrightnessConfig::default()
+            };
+            let levels: Vec<(f32, f32)> = (10..=200)
+                .map(|value| {
+                    let brightness = Brightness {
+                        value: value as f32,
+                        limits: limits.clone(),
+                    };
+                    (brightness.to_redshift(), brightness.to_light())
+                })
+                .collect();
+            assert!(
+                levels
+                    .windows(2)
+                    .all(|pair| pair[1].0 >= pair[0].0 && pair[1].1 >= pair[0].1),
+                "{:?} is not monotonic",
+                curve
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_curves_are_rejected() {
+        assert!(Curve::Power { exponent: 0.0 }.validate().is_err());
+        assert!(Curve::Logarithmic { base: 1.0 }.validate().is_err());
+        assert!(Curve::Points {
+            points: vec![[0.0, 0.0], [0.5, 0.6], [0.4, 0.7], [1.0, 1.0]]
+        }
+        .validate()
+        .is_err());
+        assert!(Curve::Points {
+            points: vec![[0.0, 0.1], [1.0, 1.0]]
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// D-Bus service for desktop shells and applets, exposing brightness as `org.sunset.Brightness`
+// on the session bus.
+//
+// A single thread owns the connection. zbus holds on to the connection while it waits for a
+// message, so signals can't be sent from other threads: instead, the socket gets a read timeout
+// and the thread sends signals for brightness changes in between calls.
+
+use crate::controller::Controller;
+use crate::error::AppError;
+use crate::{transition, AppData};
+use failure::{format_err, Error};
+use log::{info, warn};
+use std::io::ErrorKind;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+use zbus::fdo::{self, DBusProxy, RequestNameFlags, RequestNameReply};
+use zbus::zvariant::ObjectPath;
+use zbus::{dbus_interface, Connection, ObjectServer};
+
+pub const BUS_NAME: &str = "org.sunset.Brightness";
+pub const OBJECT_PATH: &str = "/org/sunset/Brightness";
+
+/// How long the service waits for a call before checking for brightness changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Service {
+    controller: Controller,
+    step: f32,
+}
+
+impl Service {
+    /// Runs `f` on the controller, turning errors into D-Bus ones
+    fn run<R, F>(&self, f: F) -> fdo::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppData) -> Result<R, AppError> + Send + 'static,
+    {
+        self.controller.run(f).map_err(|err| match err {
+            AppError::InvalidValue(message) => fdo::Error::InvalidArgs(message),
+            err => fdo::Error::Failed(err.to_string()),
+        })
+    }
+
+    /// Starts moving towards the target `to` picks from where the brightness is heading, returning
+    /// the value it ends up at
+    fn change(&self, to: impl FnOnce(f32) -> f32 + Send + 'static) -> fdo::Result<f64> {
+        self.run(move |data| {
+            let target = data.brightness.clamped(to(transition::target(data)));
+            data.manual_override = true;
+            transition::start(data, target, None)?;
+            Ok(target as f64)
+        })
+    }
+}
+
+#[dbus_interface(name = "org.sunset.Brightness")]
+impl Service {
+    /// Current position on the brightness scale
+    fn get(&self) -> fdo::Result<f64> {
+        self.run(|data| Ok(data.brightness.value as f64))
+    }
+
+    /// Changes the brightness, returning the new value after clamping
+    fn set(&self, brightness: f64) -> fdo::Result<f64> {
+        if !brightness.is_finite() {
+            return Err(fdo::Error::InvalidArgs(
+                "brightness must be a number".into(),
+            ));
+        }
+        self.change(move |_| brightness as f32)
+    }
+
+    /// Changes the brightness by a number of steps (negative ones dim the screen), returning the
+    /// new value
+    fn step(&self, steps: i32) -> fdo::Result<f64> {
+        let amount = steps as f32 * self.step;
+        self.change(move |value| value + amount)
+    }
+
+    /// Sent whenever the brightness changes, whoever changed it
+    #[dbus_interface(signal)]
+    fn changed(&self, brightness: f64) -> zbus::Result<()>;
+}
+
+/// Connects to a bus given its D-Bus address (`unix:path=...` or `unix:abstract=...`)
+fn connect(address: &str) -> Result<UnixStream, Error> {
+    // Anything after the first comma is a guid or other option we don't need
+    let transport = address.split(',').next().unwrap_or_default();
+    let stream = if let Some(path) = transport.strip_prefix("unix:path=") {
+        UnixStream::connect(path)?
+    } else if let Some(name) = transport.strip_prefix("unix:abstract=") {
+        UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)?
+    } else {
+        return Err(format_err!("Unsupported D-Bus address '{}'", address));
+    };
+    Ok(stream)
+}
+
+fn session_address() -> Result<String, Error> {
+    if let Ok(address) = std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+        return Ok(address);
+    }
+    let runtime_dir = dirs::runtime_dir().ok_or_else(|| {
+        format_err!("Neither DBUS_SESSION_BUS_ADDRESS nor XDG_RUNTIME_DIR is set")
+    })?;
+    Ok(format!("unix:path={}", runtime_dir.join("bus").display()))
+}
+
+/// Claims the bus name and starts serving calls on its own thread. Connects to the session bus
+/// unless given another bus address.
+pub fn spawn(address: Option<&str>, controller: Controller, step: f32) -> Result<(), Error> {
+    let address = match address {
+        Some(address) => address.to_string(),
+        None => session_address()?,
+    };
+    let stream = connect(&address)
+        .map_err(|err| format_err!("Could not connect to D-Bus at {}: {}", address, err))?;
+    let timeout_handle = stream.try_clone()?;
+    let connection = Connection::new_unix_client(stream, true)?;
+
+    let reply = DBusProxy::new(&connection)?
+        .request_name(BUS_NAME, RequestNameFlags::DoNotQueue.into())
+        .map_err(|err| format_err!("Could not claim {}: {}", BUS_NAME, err))?;
+    if reply != RequestNameReply::PrimaryOwner {
+        return Err(format_err!(
+            "{} is already taken, is another daemon running?",
+            BUS_NAME
+        ));
+    }
+
+    // Only set after the handshake and name request, which expect blocking reads
+    timeout_handle.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let (sender, changes) = channel();
+    controller.run(move |data| {
+        data.subscribe(move |change| sender.send(change.brightness).is_ok());
+        Ok(())
+    })?;
+    let service = Service { controller, step };
+    std::thread::Builder::new()
+        .name("dbus".into())
+        .spawn(move || run(connection, service, changes))?;
+    info!("Serving {} on D-Bus", BUS_NAME);
+    Ok(())
+}
+
+fn run(connection: Connection, service: Service, changes: Receiver<f32>) {
+    let path = ObjectPath::from_static_str_unchecked(OBJECT_PATH);
+    let mut server = ObjectServer::new(&connection);
+    if let Err(err) = server.at(&path, service) {
+        warn!("Could not register the D-Bus object: {}", err);
+        return;
+    }
+
+    let mut last_brightness = None;
+    loop {
+        match server.try_handle_next() {
+            // Messages that aren't calls for us, like NameAcquired, don't need handling
+            Ok(_) => {}
+            Err(zbus::Error::Io(err))
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+            Err(err) => {
+                warn!("D-Bus service stopped: {}", err);
+                return;
+            }
+        }
+
+        // Only the latest value matters if several changes piled up
+        if let Some(brightness) = changes.try_iter().last() {
+            if last_brightness == Some(brightness) {
+                continue;
+            }
+            last_brightness = Some(brightness);
+            let emitted = server.with(&path, |service: &Service| {
+                service.changed(brightness as f64)
+            });
+            if let Err(err) = emitted {
+                warn!("Could not send the D-Bus Changed signal: {}", err);
+            }
+        }
+    }
+}
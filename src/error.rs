@@ -0,0 +1,160 @@
+// Errors that make it back to clients. Backends keep returning `failure::Error`, which may wrap
+// one of these when they know what went wrong; anything else is reported as the backend being
+// unavailable.
+
+use actix_web::http::header::WWW_AUTHENTICATE;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use failure::Fail;
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AppError {
+    /// The request asked for something that makes no sense, like a brightness of NaN
+    InvalidValue(String),
+    UnknownOutput(String),
+    /// The resource doesn't support the method, with the ones it does support
+    MethodNotAllowed(String),
+    /// The request is missing the bearer token, or has the wrong one
+    Unauthorized,
+    /// The backend can't do anything right now: a missing binary or device, no permission, a
+    /// compositor that went away...
+    BackendUnavailable(String),
+    /// A command ran but reported failure
+    CommandFailed {
+        command: String,
+        /// Exit status, missing when killed by a signal
+        status: Option<i32>,
+        stderr: String,
+    },
+    /// A command (which then got killed) or a backend thread took too long
+    Timeout {
+        command: String,
+        timeout: Duration,
+    },
+    /// The daemon is exiting, and not taking any more changes
+    ShuttingDown,
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::InvalidValue(message)
+            | AppError::MethodNotAllowed(message)
+            | AppError::BackendUnavailable(message) => write!(f, "{}", message),
+            AppError::UnknownOutput(name) => write!(f, "No output called {}", name),
+            AppError::Unauthorized => write!(f, "missing or invalid bearer token"),
+            AppError::Timeout { command, timeout } => write!(
+                f,
+                "{} did not finish within {} ms",
+                command,
+                timeout.as_millis()
+            ),
+            AppError::ShuttingDown => write!(f, "The daemon is shutting down"),
+            AppError::CommandFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                match status {
+                    Some(status) => write!(f, "{} exited with status {}", command, status)?,
+                    None => write!(f, "{} was killed", command)?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Fail for AppError {}
+
+impl AppError {
+    pub fn command_failed(command: &str, status: std::process::ExitStatus, stderr: &[u8]) -> Self {
+        AppError::CommandFailed {
+            command: command.to_string(),
+            status: status.code(),
+            stderr: String::from_utf8_lossy(stderr).trim().to_string(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            AppError::InvalidValue(_) => "invalid_value",
+            AppError::UnknownOutput(_) => "unknown_output",
+            AppError::MethodNotAllowed(_) => "method_not_allowed",
+            AppError::Unauthorized => "unauthorized",
+            AppError::BackendUnavailable(_) => "backend_unavailable",
+            AppError::CommandFailed { .. } => "command_failed",
+            AppError::Timeout { .. } => "timeout",
+            AppError::ShuttingDown => "shutting_down",
+        }
+    }
+}
+
+impl From<failure::Error> for AppError {
+    fn from(err: failure::Error) -> Self {
+        match err.downcast::<AppError>() {
+            Ok(err) => err,
+            Err(err) => AppError::BackendUnavailable(err.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    /// Human readable description
+    error: String,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<&'a str>,
+}
+
+impl ResponseError for AppError {
+    fn error_response(&self) -> HttpResponse {
+        let status = match self {
+            AppError::InvalidValue(_) => StatusCode::BAD_REQUEST,
+            AppError::UnknownOutput(_) => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::BackendUnavailable(_) | AppError::ShuttingDown => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AppError::CommandFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+        };
+        let (command, exit_status, stderr) = match self {
+            AppError::CommandFailed {
+                command,
+                status,
+                stderr,
+            } => (Some(command.as_str()), *status, Some(stderr.as_str())),
+            AppError::Timeout { command, .. } => (Some(command.as_str()), None, None),
+            _ => (None, None, None),
+        };
+        let mut response = HttpResponse::build(status);
+        if let AppError::Unauthorized = self {
+            response.header(WWW_AUTHENTICATE, "Bearer");
+        }
+        response.json(ErrorBody {
+            error: self.to_string(),
+            kind: self.kind(),
+            command,
+            status: exit_status,
+            stderr,
+        })
+    }
+
+    // The default replaces the body with the message as plain text
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
@@ -1,4 +1,5 @@
 extern crate actix_web;
+extern crate chrono;
 extern crate dirs;
 extern crate failure;
 extern crate fern;
@@ -6,133 +7,396 @@ extern crate log;
 extern crate parking_lot;
 
 use actix_web::{web, App, HttpServer};
-use failure::Error;
-use std::process::Child;
-use std::sync::Arc;
+use futures::Future;
 
-use parking_lot::Mutex;
-use serde::Deserialize;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Duration;
 
+use backend::{Backend, OutputInfo};
+use config::{BrightnessConfig, TransitionConfig};
+use controller::Controller;
+use error::AppError;
+use structopt::StructOpt;
+
+mod api;
+mod auth;
+mod backend;
+mod cli;
+mod client;
+mod config;
+mod controller;
+mod curve;
+mod dbus;
+mod error;
+mod outputs;
+mod scheduler;
+mod socket;
+mod solar;
+mod state;
+#[cfg(test)]
+mod tests;
+mod transition;
+mod watcher;
+
+/// Lowest backlight level, as a percentage, that doesn't turn the screen off
+const MIN_BACKLIGHT: f32 = 0.10673;
+
+/// Seconds open connections (like event streams) get to finish when the daemon is stopped
+const SHUTDOWN_TIMEOUT: u64 = 1;
+
+#[derive(Clone)]
 struct Brightness {
     value: f32,
+    limits: BrightnessConfig,
 }
 
 impl Brightness {
     fn to_light(&self) -> f32 {
-        if self.value < 100.10673f32 {
-            0.10673
-        } else {
-            self.value - 100f32
-        }
+        let position = f64::from(self.value - self.limits.pivot) / self.backlight_range();
+        ((self.limits.curve.apply(position) * 100.0) as f32).max(MIN_BACKLIGHT)
     }
 
     fn to_redshift(&self) -> f32 {
-        if self.value > 100f32 {
-            1.0
-        } else {
-            self.value / 100.0
-        }
+        let position = f64::from(self.value) / f64::from(self.limits.pivot);
+        self.limits.curve.apply(position) as f32
     }
 
-    fn change(&mut self, amount: f32) {
-        self.set(self.value + amount);
+    /// Sets the brightness matching the given backlight percentage
+    fn set_from_light(&mut self, percent: f32) {
+        let position = self.limits.curve.position(f64::from(percent) / 100.0);
+        self.set(self.limits.pivot + (position * self.backlight_range()) as f32);
+    }
+
+    /// Span of the scale above the pivot, where the backlight goes from 0% to 100%
+    fn backlight_range(&self) -> f64 {
+        f64::from(self.limits.max - self.limits.pivot)
     }
 
     fn set(&mut self, value: f32) {
-        self.value = value.max(10.0).min(200.0);
+        self.value = self.clamped(value);
+    }
+
+    fn clamped(&self, value: f32) -> f32 {
+        value.clamp(self.limits.min, self.limits.max)
+    }
+}
+
+struct Temperature {
+    /// Color temperature, in Kelvin
+    value: u32,
+    min: u32,
+    max: u32,
+}
+
+impl Temperature {
+    fn change(&mut self, amount: i32) {
+        self.set((self.value as i32 + amount).max(0) as u32);
+    }
+
+    fn set(&mut self, value: u32) {
+        self.value = value.clamp(self.min, self.max);
     }
 }
 
-struct AppData {
+pub struct AppData {
+    brightness: Brightness,
+    temperature: Temperature,
+    /// Set when the user changes brightness by hand, so the scheduler leaves it alone
+    manual_override: bool,
+    backend: Box<dyn Backend>,
+    /// Where the state gets saved after every change, if anywhere
+    state_file: Option<PathBuf>,
+    transition: TransitionConfig,
+    running_transition: Option<transition::Transition>,
+    /// Called after every change, until they return false
+    listeners: Vec<Box<dyn FnMut(Change) -> bool + Send>>,
+    /// Last state listeners were told about
+    notified: Option<Change>,
+    /// Outputs whose brightness was set on its own. Changing the brightness of everything
+    /// brings them back in line.
+    outputs: BTreeMap<String, OutputState>,
+    /// Backlight outputs that were set on their own and then released. Global changes only drive
+    /// the main backlight device, so these get the global value pushed to them as well.
+    following: BTreeSet<String>,
+    /// Names that address several outputs at once
+    output_groups: BTreeMap<String, Vec<String>>,
+    /// Counts the times values were pushed to the backend, so the watcher can tell our own
+    /// backlight changes from those of other programs
+    applied: u64,
+}
+
+struct OutputState {
     brightness: Brightness,
-    redshift_process: Child,
+    info: OutputInfo,
+}
+
+/// What listeners get told about when the state changes
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Change {
+    brightness: f32,
+    temperature: u32,
+    manual_override: bool,
 }
 
 impl AppData {
-    fn kill_child(&mut self) {
-        if let Err(err) = self.redshift_process.kill() {
-            eprintln!("Could not kill redshift process: {}", err);
+    /// Applies the current values, then saves them and tells listeners even if the backend
+    /// failed, since they are still what the screen should be at
+    fn restart(&mut self) -> Result<(), AppError> {
+        let result = self.apply();
+        self.save_state();
+        self.notify();
+        result
+    }
+
+    fn subscribe(&mut self, listener: impl FnMut(Change) -> bool + Send + 'static) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    fn current(&self) -> Change {
+        Change {
+            brightness: self.brightness.value,
+            temperature: self.temperature.value,
+            manual_override: self.manual_override,
         }
-        if let Err(err) = self.redshift_process.wait() {
-            eprintln!("Could not wait on killed redshift process: {}", err);
+    }
+
+    /// Tells listeners about the current state, if it changed since they last heard
+    fn notify(&mut self) {
+        let change = self.current();
+        if self.notified == Some(change) {
+            return;
         }
+        self.notified = Some(change);
+        self.listeners.retain_mut(|listener| listener(change));
     }
 
-    fn restart(&mut self) {
-        self.kill_child();
-        run_light(self.brightness.to_light()).unwrap();
+    /// Pushes the current values to the backend
+    fn apply(&mut self) -> Result<(), AppError> {
+        self.applied += 1;
+        let backlight = self.backend.set_backlight(self.brightness.to_light());
+        let gamma = self
+            .backend
+            .set_gamma(self.brightness.to_redshift(), self.temperature.value);
+        self.apply_outputs();
+        backlight.and(gamma).map_err(AppError::from)
+    }
 
-        let child = run_redshift(self.brightness.to_redshift()).unwrap();
-        self.redshift_process = child;
+    /// Makes an output set on its own follow everything else again, from the next time the
+    /// values are applied
+    fn release_output(&mut self, name: &str) {
+        if let Some(output) = self.outputs.remove(name) {
+            if output.info.backlight {
+                self.following.insert(name.to_string());
+            }
+        }
+    }
+
+    fn release_outputs(&mut self) {
+        let names: Vec<String> = self.outputs.keys().cloned().collect();
+        for name in names {
+            self.release_output(&name);
+        }
+    }
+
+    /// Pushes the values of outputs set on their own, on top of what everything else gets
+    fn apply_outputs(&mut self) {
+        let backend = &mut self.backend;
+        let temperature = self.temperature.value;
+        let percent = self.brightness.to_light();
+        for name in &self.following {
+            if let Err(err) = backend.set_output_backlight(name, percent) {
+                warn!("Could not adjust output {}: {}", name, err);
+            }
+        }
+        for (name, output) in &self.outputs {
+            let mut result = Ok(());
+            if output.info.backlight {
+                result = backend.set_output_backlight(name, output.brightness.to_light());
+            }
+            if output.info.gamma {
+                result = result.and_then(|_| {
+                    backend.set_output_gamma(name, output.brightness.to_redshift(), temperature)
+                });
+            }
+            if let Err(err) = result {
+                warn!("Could not adjust output {}: {}", name, err);
+            }
+        }
+    }
+
+    /// Stops transitions and releases the backend. Given the backlight level found at startup,
+    /// the screen is also put back the way it was.
+    fn shutdown(&mut self, original_backlight: Option<f32>) {
+        transition::cancel(self);
+        if let Some(percent) = original_backlight {
+            if let Err(err) = self.backend.set_backlight(percent) {
+                warn!("Could not restore the backlight: {}", err);
+            }
+            // Nothing is known about gamma from before, but neutral is what it almost always is
+            if let Err(err) = self.backend.set_gamma(1.0, 6500) {
+                warn!("Could not restore gamma: {}", err);
+            }
+        }
+        self.backend.shutdown();
+    }
+
+    fn save_state(&self) {
+        if let Some(path) = &self.state_file {
+            let state = state::PersistedState {
+                brightness: self.brightness.value,
+                temperature: self.temperature.value,
+                manual_override: self.manual_override,
+            };
+            if let Err(err) = state::save(path, &state) {
+                warn!("Could not save state to {}: {}", path.display(), err);
+            }
+        }
     }
 }
 
-fn run_light(brightness: f32) -> Result<(), Error> {
-    std::process::Command::new("light")
-        .arg("-S")
-        .arg(format!("{}", brightness))
-        .spawn()?
-        .wait()?;
+#[derive(Deserialize)]
+struct Request {
+    brightness: f32,
+    /// How long the change should take. Defaults to the configured transition duration.
+    duration_ms: Option<u64>,
+}
 
-    Ok(())
+fn set_handler(
+    req: web::Query<Request>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = String, Error = AppError> {
+    let target = req.brightness;
+    let duration = req.duration_ms.map(Duration::from_millis);
+    data.controller.call(move |data| {
+        if !target.is_finite() {
+            return Err(AppError::InvalidValue("brightness must be a number".into()));
+        }
+        data.manual_override = true;
+        transition::start(data, target, duration)?;
+        Ok(format!("{}", transition::target(data)))
+    })
 }
 
-fn run_redshift(brightness: f32) -> Result<std::process::Child, Error> {
-    let child = std::process::Command::new("redshift")
-        .arg("-m")
-        .arg("wayland")
-        .arg("-O")
-        .arg("6500")
-        .arg("-b")
-        .arg(format!("{}", brightness))
-        .spawn()?;
+fn get_handler(data: web::Data<AppState>) -> impl Future<Item = String, Error = AppError> {
+    data.controller
+        .call(|data| Ok(format!("{}", data.brightness.value)))
+}
 
-    Ok(child)
+#[derive(Deserialize)]
+struct TemperatureRequest {
+    kelvin: u32,
 }
 
-fn get_screen_brightness() -> Result<Brightness, failure::Error> {
-    let output = std::process::Command::new("light").output()?.stdout;
-    let output_str = std::str::from_utf8(&output)?;
-    println!("Light output: {}", output_str);
-    let result: f32 = output_str.trim().parse()?;
+fn temperature_set_handler(
+    req: web::Query<TemperatureRequest>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = (), Error = AppError> {
+    let kelvin = req.kelvin;
+    data.controller.call(move |data| {
+        data.temperature.set(kelvin);
+        data.manual_override = true;
+        data.restart()
+    })
+}
 
-    Ok(Brightness {
-        value: result + 100.0,
+fn temperature_get_handler(
+    data: web::Data<AppState>,
+) -> impl Future<Item = String, Error = AppError> {
+    data.controller
+        .call(|data| Ok(format!("{}", data.temperature.value)))
+}
+
+/// Changes the temperature by `steps` steps
+fn change_temperature(state: &AppState, steps: i32) -> impl Future<Item = (), Error = AppError> {
+    let amount = steps * state.temperature_step as i32;
+    state.controller.call(move |data| {
+        data.temperature.change(amount);
+        data.manual_override = true;
+        data.restart()
     })
 }
 
-#[derive(Deserialize)]
-struct Request {
-    brightness: f32,
+fn warmer_handler(data: web::Data<AppState>) -> impl Future<Item = (), Error = AppError> {
+    change_temperature(&data, -1)
+}
+
+fn cooler_handler(data: web::Data<AppState>) -> impl Future<Item = (), Error = AppError> {
+    change_temperature(&data, 1)
 }
 
-fn set_handler(req: web::Query<Request>, data: web::Data<AppState>) -> Result<(), Error> {
-    data.data.lock().brightness.set(req.brightness);
-    data.data.lock().restart();
-    Ok(())
+/// Changes the brightness by `steps` steps, answering with the brightness it's heading to
+fn change_brightness(state: &AppState, steps: f32) -> impl Future<Item = String, Error = AppError> {
+    let amount = steps * state.brightness_step;
+    state.controller.call(move |data| {
+        data.manual_override = true;
+        // From where a running transition is heading, so repeated steps add up
+        let target = transition::target(data) + amount;
+        transition::start(data, target, None)?;
+        Ok(format!("{}", transition::target(data)))
+    })
 }
 
-fn get_handler(data: web::Data<AppState>) -> Result<String, Error> {
-    Ok(format!("{}", data.data.lock().brightness.value))
+fn brighter_handler(data: web::Data<AppState>) -> impl Future<Item = String, Error = AppError> {
+    change_brightness(&data, 1.0)
 }
 
-fn brighter_handler(data: web::Data<AppState>) -> Result<(), Error> {
-    data.data.lock().brightness.change(5.0);
-    data.data.lock().restart();
-    Ok(())
+fn darker_handler(data: web::Data<AppState>) -> impl Future<Item = String, Error = AppError> {
+    change_brightness(&data, -1.0)
 }
 
-fn darker_handler(data: web::Data<AppState>) -> Result<(), Error> {
-    data.data.lock().brightness.change(-5.0);
-    data.data.lock().restart();
-    Ok(())
+fn routes(cfg: &mut web::ServiceConfig) {
+    // Bad query strings get the same JSON errors as everything else, not actix's plain text
+    cfg.data(
+        web::QueryConfig::default()
+            .error_handler(|err, _| AppError::InvalidValue(err.to_string()).into()),
+    );
+    cfg.route("/get", web::get().to_async(get_handler))
+        .route("/set", web::get().to_async(set_handler))
+        .route("/brighter", web::get().to_async(brighter_handler))
+        .route("/darker", web::get().to_async(darker_handler))
+        .route(
+            "/temperature/get",
+            web::get().to_async(temperature_get_handler),
+        )
+        .route(
+            "/temperature/set",
+            web::get().to_async(temperature_set_handler),
+        )
+        .route("/warmer", web::get().to_async(warmer_handler))
+        .route("/cooler", web::get().to_async(cooler_handler));
+    api::routes(cfg);
+    outputs::routes(cfg);
 }
 
 struct AppState {
-    data: Arc<Mutex<AppData>>,
+    controller: Controller,
+    brightness_step: f32,
+    temperature_step: u32,
+    /// For `/health`, which probes the backend afresh
+    backend_config: backend::BackendConfig,
 }
 
 fn main() {
+    let options = cli::Options::from_args();
+    let config = match config::Config::load(&options.overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    match options.command {
+        None | Some(cli::Command::Daemon) => run_daemon(config),
+        Some(cli::Command::Doctor) => std::process::exit(client::doctor(&config)),
+        Some(command) => std::process::exit(client::run(&command, &config.server)),
+    }
+}
+
+fn run_daemon(config: config::Config) {
     let home = dirs::home_dir()
         .unwrap()
         .into_os_string()
@@ -156,28 +420,169 @@ fn main() {
         .apply()
         .expect("Could not initialize logging");
 
+    let saved_state = config
+        .state_file
+        .as_ref()
+        .and_then(|path| match state::load(path) {
+            Ok(saved_state) => saved_state,
+            Err(err) => {
+                warn!("Ignoring saved state: {}", err);
+                None
+            }
+        });
+
+    // Before the backend is set up, so failing here leaves nothing behind
+    let token = match auth::load_or_create_token(&config.server) {
+        Ok(token) => token,
+        Err(err) => {
+            eprintln!("Could not set up authentication: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if token.is_none() {
+        warn!("Authentication is disabled: anyone who can reach the server can control the screen");
+    }
+
+    let backend = backend::create(&config.backend).expect("Could not initialize backend");
+
+    let mut data = AppData {
+        brightness: Brightness {
+            value: config.brightness.max,
+            limits: config.brightness.clone(),
+        },
+        temperature: Temperature {
+            // 6500K is neutral white: redshift leaves colors untouched at that temperature
+            value: 6500,
+            min: config.temperature.min,
+            max: config.temperature.max,
+        },
+        manual_override: false,
+        backend,
+        state_file: config.state_file.clone(),
+        transition: config.transition,
+        running_transition: None,
+        listeners: Vec::new(),
+        notified: None,
+        outputs: BTreeMap::new(),
+        following: BTreeSet::new(),
+        output_groups: config.output_groups.clone(),
+        applied: 0,
+    };
+
+    let original_backlight = data.backend.read_backlight();
+    match saved_state {
+        Some(saved_state) => {
+            data.brightness.set(saved_state.brightness);
+            data.temperature.set(saved_state.temperature);
+            data.manual_override = saved_state.manual_override;
+        }
+        None => {
+            let backlight = original_backlight
+                .as_ref()
+                .expect("Could not read screen brightness");
+            data.brightness.set_from_light(*backlight);
+            data.temperature.set(6500);
+        }
+    }
+    let original_backlight = match original_backlight {
+        Ok(percent) if config.backend.restore_on_exit => Some(percent),
+        Ok(_) => None,
+        Err(err) => {
+            if config.backend.restore_on_exit {
+                warn!("The backlight won't be restored on exit: {}", err);
+            }
+            None
+        }
+    };
+    if let Err(err) = data.restart() {
+        error!("Could not set up the screen: {}", err);
+    }
+    println!("Initial brightness value: {}", data.brightness.value);
+
+    let controller = Controller::spawn(data);
     let app_state = web::Data::new(AppState {
-        data: Arc::new(Mutex::new(AppData {
-            brightness: get_screen_brightness().expect("Could not invoke 'light' command"),
-            redshift_process: run_redshift(1.0).expect("Could not launch redshift"),
-        })),
+        controller: controller.clone(),
+        brightness_step: config.brightness.step,
+        temperature_step: config.temperature.step,
+        backend_config: config.backend.clone(),
     });
 
-    println!(
-        "Initial brightness value: {}",
-        app_state.data.lock().brightness.value
-    );
+    if config.server.dbus {
+        if let Err(err) = dbus::spawn(None, controller.clone(), config.brightness.step) {
+            warn!("D-Bus service disabled: {}", err);
+        }
+    }
 
-    HttpServer::new(move || {
-        App::new()
-            .register_data(app_state.clone())
-            .route("/get", web::get().to(get_handler))
-            .route("/set", web::get().to(set_handler))
-            .route("/brighter", web::get().to(brighter_handler))
-            .route("/darker", web::get().to(darker_handler))
-    })
-    .bind("0.0.0.0:12321")
-    .expect("Can not bind to port 12321")
-    .run()
-    .expect("Could not run web server");
+    if config.backend.watch_interval > Duration::from_millis(0) {
+        watcher::spawn(config.backend.watch_interval, controller.clone());
+    }
+
+    if let Some(schedule) = config.schedule {
+        scheduler::spawn(schedule, controller.clone());
+    }
+
+    // Stopping the controller makes sure nothing touches the screen after the backend is released
+    let shutdown = move || {
+        let _ = controller.stop(move |data| {
+            data.shutdown(original_backlight);
+            Ok(())
+        });
+    };
+
+    let server = &config.server;
+
+    // Both servers stop on SIGINT, SIGTERM or SIGQUIT, which ends `system.run()`
+    let system = actix_rt::System::new("sunset");
+
+    if server.tcp {
+        let app_state = app_state.clone();
+        HttpServer::new(move || {
+            App::new()
+                .wrap(auth::BearerAuth::new(token.as_deref()))
+                .register_data(app_state.clone())
+                .configure(routes)
+        })
+        .shutdown_timeout(SHUTDOWN_TIMEOUT)
+        .bind((server.address.as_str(), server.port))
+        .unwrap_or_else(|err| {
+            eprintln!(
+                "Can not bind to {}:{}: {}",
+                server.address, server.port, err
+            );
+            shutdown();
+            std::process::exit(1);
+        })
+        .start();
+    }
+
+    // File permissions already keep other users off the socket, so it doesn't check tokens
+    if let Some(path) = &server.socket {
+        let listener = socket::bind(path).unwrap_or_else(|err| {
+            eprintln!("Can not bind to {}: {}", path.display(), err);
+            shutdown();
+            std::process::exit(1);
+        });
+        HttpServer::new(move || {
+            App::new()
+                .register_data(app_state.clone())
+                .configure(routes)
+        })
+        .shutdown_timeout(SHUTDOWN_TIMEOUT)
+        .listen_uds(listener)
+        .unwrap_or_else(|err| {
+            eprintln!("Can not listen on {}: {}", path.display(), err);
+            socket::remove(path);
+            shutdown();
+            std::process::exit(1);
+        })
+        .start();
+    }
+
+    system.run().expect("Could not run web server");
+    info!("Shutting down");
+
+    if let Some(path) = &server.socket {
+        socket::remove(path);
+    }
+    shutdown();
 }
@@ -0,0 +1,136 @@
+// Brightness for individual outputs, for setups where screens need different levels (say, a
+// laptop panel dimmed through its backlight next to an external monitor that only has gamma).
+//
+// Names are either outputs reported by the backend or groups from `output_groups`. Changing the
+// brightness of everything through the other endpoints brings every output back in line.
+
+use crate::backend::OutputInfo;
+use crate::error::AppError;
+use crate::{AppData, AppState, OutputState};
+use actix_web::{web, HttpResponse};
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+struct OutputStatus {
+    #[serde(flatten)]
+    info: OutputInfo,
+    /// Position on the brightness scale
+    brightness: f32,
+    /// Whether it was set on its own, rather than following everything else
+    individual: bool,
+}
+
+#[derive(Serialize)]
+struct Outputs {
+    outputs: Vec<OutputStatus>,
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+fn brightness_of(data: &AppData, name: &str) -> f32 {
+    match data.outputs.get(name) {
+        Some(output) => output.brightness.value,
+        None => data.brightness.value,
+    }
+}
+
+/// Finds the outputs a name refers to
+fn resolve(data: &mut AppData, name: &str) -> Result<Vec<OutputInfo>, AppError> {
+    let available = data.backend.outputs();
+    let names = match data.output_groups.get(name) {
+        Some(members) => members.clone(),
+        None => vec![name.to_string()],
+    };
+    names
+        .iter()
+        .map(|name| {
+            available
+                .iter()
+                .find(|output| &output.name == name)
+                .cloned()
+                .ok_or_else(|| AppError::UnknownOutput(name.clone()))
+        })
+        .collect()
+}
+
+fn list_handler(data: web::Data<AppState>) -> impl Future<Item = HttpResponse, Error = AppError> {
+    data.controller
+        .call(|data| {
+            let outputs = data
+                .backend
+                .outputs()
+                .into_iter()
+                .map(|info| OutputStatus {
+                    brightness: brightness_of(data, &info.name),
+                    individual: data.outputs.contains_key(&info.name),
+                    info,
+                })
+                .collect();
+            Ok(Outputs {
+                outputs,
+                groups: data.output_groups.clone(),
+            })
+        })
+        .map(|outputs| HttpResponse::Ok().json(outputs))
+}
+
+fn get_handler(
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = String, Error = AppError> {
+    data.controller.call(move |data| {
+        // Members of a group normally share a value, so any of them will do. Groups can't be
+        // empty.
+        let outputs = resolve(data, &name)?;
+        Ok(format!("{}", brightness_of(data, &outputs[0].name)))
+    })
+}
+
+#[derive(Deserialize)]
+struct Request {
+    brightness: f32,
+}
+
+fn set_handler(
+    name: web::Path<String>,
+    req: web::Query<Request>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = (), Error = AppError> {
+    let value = req.brightness;
+    data.controller.call(move |data| {
+        if !value.is_finite() {
+            return Err(AppError::InvalidValue("brightness must be a number".into()));
+        }
+
+        for info in resolve(data, &name)? {
+            let mut brightness = data.brightness.clone();
+            brightness.set(value);
+            data.following.remove(&info.name);
+            data.outputs
+                .insert(info.name.clone(), OutputState { brightness, info });
+        }
+        data.manual_override = true;
+        data.restart()
+    })
+}
+
+/// Makes outputs follow everything else again
+fn reset_handler(
+    name: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Future<Item = (), Error = AppError> {
+    data.controller.call(move |data| {
+        for info in resolve(data, &name)? {
+            data.release_output(&info.name);
+        }
+        data.restart()
+    })
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/outputs", web::get().to_async(list_handler))
+        .route("/outputs/{name}/get", web::get().to_async(get_handler))
+        .route("/outputs/{name}/set", web::get().to_async(set_handler))
+        .route("/outputs/{name}/reset", web::get().to_async(reset_handler));
+}
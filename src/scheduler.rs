@@ -0,0 +1,204 @@
+// Drives brightness and color temperature from the position of the sun.
+//
+// The sun elevation is mapped to a phase: full day values above DAY_ELEVATION, full night
+// values once civil twilight is over, and a linear blend in between. A manual change through
+// the HTTP endpoints suspends the schedule until the next phase change.
+
+use crate::config::ScheduleConfig;
+use crate::controller::Controller;
+use crate::error::AppError;
+use crate::solar;
+use crate::transition;
+use chrono::{Local, Utc};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Above this elevation (degrees) the day values are fully applied
+const DAY_ELEVATION: f64 = 3.0;
+
+/// Changes smaller than these are not worth restarting redshift for
+const BRIGHTNESS_EPSILON: f32 = 0.5;
+const TEMPERATURE_EPSILON: u32 = 25;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Day,
+    Transition,
+    Night,
+}
+
+pub struct Target {
+    pub phase: Phase,
+    pub brightness: f32,
+    pub temperature: u32,
+}
+
+/// How far into the day we are: 0.0 is full night, 1.0 is full day
+fn day_factor(elevation: f64) -> f64 {
+    let range = DAY_ELEVATION - solar::CIVIL_TWILIGHT_ELEVATION;
+    ((elevation - solar::CIVIL_TWILIGHT_ELEVATION) / range).clamp(0.0, 1.0)
+}
+
+pub fn target(config: &ScheduleConfig, elevation: f64) -> Target {
+    let factor = day_factor(elevation);
+    let phase = if factor >= 1.0 {
+        Phase::Day
+    } else if factor <= 0.0 {
+        Phase::Night
+    } else {
+        Phase::Transition
+    };
+
+    let blend = |night: f32, day: f32| night + (day - night) * factor as f32;
+
+    Target {
+        phase,
+        brightness: blend(config.night_brightness, config.day_brightness),
+        temperature: blend(
+            config.night_temperature as f32,
+            config.day_temperature as f32,
+        )
+        .round() as u32,
+    }
+}
+
+fn log_sun_times(config: &ScheduleConfig) {
+    let times = solar::sun_times(config.location(), Utc::today().naive_utc());
+    let format = |time: Option<chrono::DateTime<Utc>>| match time {
+        Some(time) => time.with_timezone(&Local).format("%H:%M").to_string(),
+        None => "--:--".to_string(),
+    };
+    info!(
+        "Today's sun times: dawn {}, sunrise {}, sunset {}, dusk {}",
+        format(times.dawn),
+        format(times.sunrise),
+        format(times.sunset),
+        format(times.dusk)
+    );
+}
+
+/// Moves towards the values for a sun at `elevation`. Returns false once the daemon is shutting
+/// down.
+pub fn update(
+    config: &ScheduleConfig,
+    controller: &Controller,
+    last_phase: &mut Option<Phase>,
+    elevation: f64,
+) -> bool {
+    let target = target(config, elevation);
+    let previous_phase = last_phase.replace(target.phase);
+
+    let result = controller.run(move |data| {
+        if previous_phase != Some(target.phase) {
+            info!(
+                "Entering {:?} phase (sun elevation {:.1}°)",
+                target.phase, elevation
+            );
+            if previous_phase.is_some() && data.manual_override {
+                info!("Resuming schedule after manual override");
+                data.manual_override = false;
+                data.notify();
+            }
+        }
+
+        if data.manual_override {
+            return Ok(());
+        }
+
+        // Compared after clamping, or an unreachable target would be chased on every update
+        let target_brightness = data.brightness.clamped(target.brightness);
+        let target_temperature = target
+            .temperature
+            .clamp(data.temperature.min, data.temperature.max);
+        let brightness_delta = (data.brightness.value - target_brightness).abs();
+        let temperature_delta = data.temperature.value.abs_diff(target_temperature);
+        if brightness_delta < BRIGHTNESS_EPSILON && temperature_delta < TEMPERATURE_EPSILON {
+            return Ok(());
+        }
+
+        data.temperature.set(target_temperature);
+        transition::start(data, target_brightness, None)
+    });
+    match result {
+        Ok(()) => true,
+        Err(AppError::ShuttingDown) => false,
+        Err(err) => {
+            warn!("Could not apply the schedule: {}", err);
+            true
+        }
+    }
+}
+
+pub fn spawn(config: ScheduleConfig, controller: Controller) {
+    std::thread::Builder::new()
+        .name("scheduler".into())
+        .spawn(move || {
+            log_sun_times(&config);
+
+            let mut last_phase = None;
+            let mut last_day = Utc::today();
+            loop {
+                if Utc::today() != last_day {
+                    last_day = Utc::today();
+                    log_sun_times(&config);
+                }
+                let elevation = solar::elevation(config.location(), Utc::now());
+                if !update(&config, &controller, &mut last_phase, elevation) {
+                    return;
+                }
+                std::thread::sleep(Duration::from_secs(config.update_interval));
+            }
+        })
+        .expect("Could not start scheduler thread");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ScheduleConfig {
+        ScheduleConfig {
+            latitude: 0.0,
+            longitude: 0.0,
+            day_brightness: 200.0,
+            day_temperature: 6500,
+            night_brightness: 100.0,
+            night_temperature: 3500,
+            update_interval: 30,
+        }
+    }
+
+    #[test]
+    fn day_values_apply_above_three_degrees() {
+        for elevation in &[DAY_ELEVATION, 10.0, 60.0] {
+            let target = target(&config(), *elevation);
+            assert_eq!(target.phase, Phase::Day);
+            assert_eq!(target.brightness, 200.0);
+            assert_eq!(target.temperature, 6500);
+        }
+    }
+
+    #[test]
+    fn twilight_blends_day_and_night_values() {
+        // Halfway between the end of civil twilight and full day
+        let halfway = target(&config(), -1.5);
+        assert_eq!(halfway.phase, Phase::Transition);
+        assert_eq!(halfway.brightness, 150.0);
+        assert_eq!(halfway.temperature, 5000);
+
+        let later = target(&config(), 0.75);
+        assert_eq!(later.phase, Phase::Transition);
+        assert_eq!(later.brightness, 175.0);
+        assert_eq!(later.temperature, 5750);
+    }
+
+    #[test]
+    fn night_values_apply_after_civil_twilight() {
+        for elevation in &[solar::CIVIL_TWILIGHT_ELEVATION, -10.0, -60.0] {
+            let target = target(&config(), *elevation);
+            assert_eq!(target.phase, Phase::Night);
+            assert_eq!(target.brightness, 100.0);
+            assert_eq!(target.temperature, 3500);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// Solar position calculations, based on the NOAA solar calculator equations.
+// Everything here is pure math: no network access, no system time zone lookups.
+
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+
+/// Sun elevation (degrees) at sunrise and sunset, accounting for atmospheric refraction
+pub const SUNRISE_ELEVATION: f64 = -0.833;
+/// Sun elevation (degrees) at the start of morning / end of evening civil twilight
+pub const CIVIL_TWILIGHT_ELEVATION: f64 = -6.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Sun events for a given day. Any of them can be missing close to the poles.
+#[derive(Clone, Copy, Debug)]
+pub struct SunTimes {
+    pub dawn: Option<DateTime<Utc>>,
+    pub sunrise: Option<DateTime<Utc>>,
+    pub sunset: Option<DateTime<Utc>>,
+    pub dusk: Option<DateTime<Utc>>,
+}
+
+struct SunParameters {
+    /// Solar declination, in radians
+    declination: f64,
+    /// Equation of time, in minutes
+    equation_of_time: f64,
+}
+
+fn julian_century(time: DateTime<Utc>) -> f64 {
+    let unix_seconds = time.timestamp() as f64 + f64::from(time.timestamp_subsec_millis()) / 1000.0;
+    let julian_day = unix_seconds / 86400.0 + 2_440_587.5;
+    (julian_day - 2_451_545.0) / 36525.0
+}
+
+fn sun_parameters(time: DateTime<Utc>) -> SunParameters {
+    let jc = julian_century(time);
+
+    let mean_longitude = (280.46646 + jc * (36000.76983 + jc * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = 357.52911 + jc * (35999.05029 - 0.0001537 * jc);
+    let eccentricity = 0.016708634 - jc * (0.000042037 + 0.0000001267 * jc);
+
+    let m = mean_anomaly.to_radians();
+    let equation_of_center = m.sin() * (1.914602 - jc * (0.004817 + 0.000014 * jc))
+        + (2.0 * m).sin() * (0.019993 - 0.000101 * jc)
+        + (3.0 * m).sin() * 0.000289;
+
+    let true_longitude = mean_longitude + equation_of_center;
+    let omega = (125.04 - 1934.136 * jc).to_radians();
+    let apparent_longitude = true_longitude - 0.00569 - 0.00478 * omega.sin();
+
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - jc * (46.815 + jc * (0.00059 - jc * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+
+    let declination = (obliquity.sin() * apparent_longitude.to_radians().sin()).asin();
+
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l0 = mean_longitude.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * l0).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l0).cos()
+            - 0.5 * y * y * (4.0 * l0).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+
+    SunParameters {
+        declination,
+        equation_of_time,
+    }
+}
+
+/// Elevation of the sun above the horizon, in degrees, at the given time and place
+pub fn elevation(location: Location, time: DateTime<Utc>) -> f64 {
+    let params = sun_parameters(time);
+
+    let minutes_of_day = f64::from(time.timestamp().rem_euclid(86400) as u32) / 60.0;
+    let true_solar_time =
+        (minutes_of_day + params.equation_of_time + 4.0 * location.longitude).rem_euclid(1440.0);
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+
+    let latitude = location.latitude.to_radians();
+    let cos_zenith = latitude.sin() * params.declination.sin()
+        + latitude.cos() * params.declination.cos() * hour_angle.cos();
+
+    90.0 - cos_zenith.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+/// Time at which the sun crosses the given elevation on the given (UTC) date, before
+/// (`rising == true`) or after solar noon. Returns None if it never reaches that elevation.
+fn crossing(
+    location: Location,
+    date: NaiveDate,
+    elevation: f64,
+    rising: bool,
+) -> Option<DateTime<Utc>> {
+    let midnight = Utc.from_utc_date(&date).and_hms(0, 0, 0);
+
+    // Two passes: the first one approximates the event time, the second one refines the sun
+    // parameters at that time.
+    let mut estimate = midnight + Duration::hours(12);
+    for _ in 0..2 {
+        let params = sun_parameters(estimate);
+        let latitude = location.latitude.to_radians();
+        let zenith = (90.0 - elevation).to_radians();
+
+        let cos_hour_angle = zenith.cos() / (latitude.cos() * params.declination.cos())
+            - latitude.tan() * params.declination.tan();
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+        let hour_angle = cos_hour_angle.acos().to_degrees();
+
+        let solar_noon = 720.0 - 4.0 * location.longitude - params.equation_of_time;
+        let minutes = if rising {
+            solar_noon - 4.0 * hour_angle
+        } else {
+            solar_noon + 4.0 * hour_angle
+        };
+        estimate = midnight + Duration::seconds((minutes * 60.0) as i64);
+    }
+    Some(estimate)
+}
+
+/// Civil dawn, sunrise, sunset and civil dusk for the given date
+pub fn sun_times(location: Location, date: NaiveDate) -> SunTimes {
+    SunTimes {
+        dawn: crossing(location, date, CIVIL_TWILIGHT_ELEVATION, true),
+        sunrise: crossing(location, date, SUNRISE_ELEVATION, true),
+        sunset: crossing(location, date, SUNRISE_ELEVATION, false),
+        dusk: crossing(location, date, CIVIL_TWILIGHT_ELEVATION, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONDON: Location = Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    };
+    const NEW_YORK: Location = Location {
+        latitude: 40.7128,
+        longitude: -74.006,
+    };
+    const SYDNEY: Location = Location {
+        latitude: -33.8688,
+        longitude: 151.2093,
+    };
+    const TROMSO: Location = Location {
+        latitude: 69.6492,
+        longitude: 18.9553,
+    };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    /// Checks `time` against a reference from the NOAA solar calculator, which rounds to the
+    /// minute
+    fn assert_near(time: Option<DateTime<Utc>>, expected: &str) {
+        let expected: DateTime<Utc> = expected.parse().unwrap();
+        let time = time.unwrap_or_else(|| panic!("no time, expected {}", expected));
+        let difference = (time - expected).num_seconds().abs();
+        assert!(difference <= 90, "{} is not close to {}", time, expected);
+    }
+
+    #[test]
+    fn matches_noaa_sunrise_and_sunset() {
+        let times = sun_times(LONDON, date(2020, 6, 21));
+        assert_near(times.sunrise, "2020-06-21T03:43:00Z");
+        assert_near(times.sunset, "2020-06-21T20:21:00Z");
+
+        let times = sun_times(LONDON, date(2020, 3, 20));
+        assert_near(times.sunrise, "2020-03-20T06:02:00Z");
+        assert_near(times.sunset, "2020-03-20T18:14:00Z");
+
+        // Sunset falls on the next UTC day
+        let times = sun_times(NEW_YORK, date(2020, 6, 21));
+        assert_near(times.sunrise, "2020-06-21T09:25:00Z");
+        assert_near(times.sunset, "2020-06-22T00:31:00Z");
+
+        // Southern hemisphere winter, sunrise on the previous UTC day
+        let times = sun_times(SYDNEY, date(2020, 6, 21));
+        assert_near(times.sunrise, "2020-06-20T21:00:00Z");
+        assert_near(times.sunset, "2020-06-21T06:54:00Z");
+    }
+
+    #[test]
+    fn twilight_surrounds_sunrise_and_sunset() {
+        let times = sun_times(LONDON, date(2020, 3, 20));
+        assert!(times.dawn.unwrap() < times.sunrise.unwrap());
+        assert!(times.sunset.unwrap() < times.dusk.unwrap());
+    }
+
+    #[test]
+    fn elevation_agrees_with_sun_times() {
+        let times = sun_times(NEW_YORK, date(2020, 6, 21));
+        for (time, expected) in [
+            (times.sunrise, SUNRISE_ELEVATION),
+            (times.sunset, SUNRISE_ELEVATION),
+            (times.dawn, CIVIL_TWILIGHT_ELEVATION),
+            (times.dusk, CIVIL_TWILIGHT_ELEVATION),
+        ] {
+            let elevation = elevation(NEW_YORK, time.unwrap());
+            assert!((elevation - expected).abs() < 0.1, "{}", elevation);
+        }
+
+        // Right overhead at noon on the equator, around the equinox
+        let equator = Location {
+            latitude: 0.0,
+            longitude: 0.0,
+        };
+        let noon = Utc.ymd(2020, 3, 20).and_hms(12, 7, 0);
+        assert!(elevation(equator, noon) > 89.0);
+        // And as far below at midnight
+        let midnight = Utc.ymd(2020, 3, 20).and_hms(0, 7, 0);
+        assert!(elevation(equator, midnight) < -89.0);
+    }
+
+    #[test]
+    fn polar_day_and_night_have_no_sunrise() {
+        // Midnight sun: the sun never sets, nor dips into twilight
+        let times = sun_times(TROMSO, date(2020, 6, 21));
+        assert!(times.dawn.is_none() && times.sunrise.is_none());
+        assert!(times.sunset.is_none() && times.dusk.is_none());
+        let solar_midnight = Utc.ymd(2020, 6, 21).and_hms(22, 45, 0);
+        assert!(elevation(TROMSO, solar_midnight) > SUNRISE_ELEVATION);
+
+        // Polar night: no sunrise, but still a few hours of civil twilight
+        let times = sun_times(TROMSO, date(2020, 12, 21));
+        assert!(times.sunrise.is_none() && times.sunset.is_none());
+        assert!(times.dawn.is_some() && times.dusk.is_some());
+        let solar_noon = Utc.ymd(2020, 12, 21).and_hms(10, 45, 0);
+        assert!(elevation(TROMSO, solar_noon) < SUNRISE_ELEVATION);
+    }
+}
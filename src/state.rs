@@ -0,0 +1,46 @@
+// Persistence of the user-visible state across daemon restarts.
+
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub brightness: f32,
+    pub temperature: u32,
+    pub manual_override: bool,
+}
+
+/// `$XDG_STATE_HOME/sunset/state.json`, falling back to `~/.local/state/sunset/state.json`
+pub fn default_path() -> Option<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()?.join(".local/state"),
+    };
+    Some(state_home.join("sunset/state.json"))
+}
+
+/// Loads the state saved at `path`. A missing file is not an error, it just means there is
+/// nothing to restore.
+pub fn load(path: &Path) -> Result<Option<PersistedState>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format_err!("Could not read {}: {}", path.display(), err)),
+    };
+    let state = serde_json::from_str(&contents)
+        .map_err(|err| format_err!("Invalid state file {}: {}", path.display(), err))?;
+    Ok(Some(state))
+}
+
+/// Saves the state to `path`, going through a temporary file so a crash mid-write can't leave
+/// a truncated state file behind.
+pub fn save(path: &Path, state: &PersistedState) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string_pretty(state)?)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,974 @@
+use super::*;
+use actix_web::dev::{Body, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::test;
+use backend::mock::{Call, Mock};
+use backend::{BackendConfig, CheckStatus, RedshiftStatus};
+
+fn app_state(brightness: f32, temperature: u32) -> (web::Data<AppState>, Mock) {
+    let mock = Mock::new(brightness - 100.0);
+    let state = app_state_with(mock.clone(), brightness, temperature, BTreeMap::new());
+    (state, mock)
+}
+
+fn app_state_with(
+    mock: Mock,
+    brightness: f32,
+    temperature: u32,
+    output_groups: BTreeMap<String, Vec<String>>,
+) -> web::Data<AppState> {
+    web::Data::new(AppState {
+        controller: Controller::spawn(AppData {
+            brightness: Brightness {
+                value: brightness,
+                limits: BrightnessConfig::default(),
+            },
+            temperature: Temperature {
+                value: temperature,
+                min: 1000,
+                max: 6500,
+            },
+            manual_override: false,
+            backend: Box::new(mock),
+            state_file: None,
+            transition: TransitionConfig::default(),
+            running_transition: None,
+            listeners: Vec::new(),
+            notified: None,
+            outputs: BTreeMap::new(),
+            following: BTreeSet::new(),
+            output_groups,
+            applied: 0,
+        }),
+        brightness_step: 5.0,
+        temperature_step: 250,
+        backend_config: BackendConfig::default(),
+    })
+}
+
+/// Runs `f` on the daemon state, the way background threads do
+fn with_data<R: Send + 'static>(
+    state: &web::Data<AppState>,
+    f: impl FnOnce(&mut AppData) -> R + Send + 'static,
+) -> R {
+    state.controller.run(move |data| Ok(f(data))).unwrap()
+}
+
+/// Sends a GET request for `uri` to a fresh app serving `state`
+fn get(state: &web::Data<AppState>, uri: &str) -> ServiceResponse<Body> {
+    let mut app = test::init_service(App::new().register_data(state.clone()).configure(routes));
+    test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request())
+}
+
+fn get_body(state: &web::Data<AppState>, uri: &str) -> String {
+    let response = get(state, uri);
+    assert_eq!(response.status(), StatusCode::OK);
+    String::from_utf8(test::read_body(response).to_vec()).unwrap()
+}
+
+#[test]
+fn get_returns_current_brightness() {
+    let (state, mock) = app_state(150.0, 6500);
+    assert_eq!(get_body(&state, "/get"), "150");
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn set_above_pivot_drives_backlight() {
+    let (state, mock) = app_state(100.0, 6500);
+    get_body(&state, "/set?brightness=150");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(50.0), Call::SetGamma(1.0, 6500)]
+    );
+    assert_eq!(get_body(&state, "/get"), "150");
+}
+
+#[test]
+fn set_below_pivot_drives_gamma() {
+    let (state, mock) = app_state(150.0, 6500);
+    get_body(&state, "/set?brightness=50");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(0.10673), Call::SetGamma(0.5, 6500)]
+    );
+}
+
+#[test]
+fn set_clamps_to_bounds() {
+    let (state, mock) = app_state(100.0, 6500);
+
+    get_body(&state, "/set?brightness=5");
+    assert_eq!(get_body(&state, "/get"), "10");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(0.10673), Call::SetGamma(0.1, 6500)]
+    );
+
+    get_body(&state, "/set?brightness=250");
+    assert_eq!(get_body(&state, "/get"), "200");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(100.0), Call::SetGamma(1.0, 6500)]
+    );
+}
+
+#[test]
+fn set_without_value_is_rejected() {
+    let (state, mock) = app_state(100.0, 6500);
+    for uri in &[
+        "/set",
+        "/set?brightness=bright",
+        "/temperature/set?kelvin=-1",
+        "/outputs/eDP-1/set",
+    ] {
+        let (status, body) = send_json(&state, test::TestRequest::get().uri(uri));
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+        assert_eq!(body["kind"], "invalid_value", "{}", uri);
+    }
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn brighter_and_darker_step_by_five() {
+    let (state, mock) = app_state(120.0, 6500);
+
+    get_body(&state, "/brighter");
+    assert_eq!(get_body(&state, "/get"), "125");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(25.0), Call::SetGamma(1.0, 6500)]
+    );
+
+    get_body(&state, "/darker");
+    get_body(&state, "/darker");
+    assert_eq!(get_body(&state, "/get"), "115");
+    assert_eq!(
+        mock.take_calls(),
+        vec![
+            Call::SetBacklight(20.0),
+            Call::SetGamma(1.0, 6500),
+            Call::SetBacklight(15.0),
+            Call::SetGamma(1.0, 6500),
+        ]
+    );
+}
+
+#[test]
+fn brighter_and_darker_clamp_at_bounds() {
+    let (state, mock) = app_state(198.0, 6500);
+    get_body(&state, "/brighter");
+    assert_eq!(get_body(&state, "/get"), "200");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(100.0), Call::SetGamma(1.0, 6500)]
+    );
+
+    let (state, mock) = app_state(12.0, 6500);
+    get_body(&state, "/darker");
+    assert_eq!(get_body(&state, "/get"), "10");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(0.10673), Call::SetGamma(0.1, 6500)]
+    );
+}
+
+#[test]
+fn temperature_endpoints_clamp_to_range() {
+    let (state, mock) = app_state(200.0, 6500);
+
+    get_body(&state, "/temperature/set?kelvin=3000");
+    assert_eq!(get_body(&state, "/temperature/get"), "3000");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(100.0), Call::SetGamma(1.0, 3000)]
+    );
+
+    get_body(&state, "/warmer");
+    assert_eq!(get_body(&state, "/temperature/get"), "2750");
+
+    get_body(&state, "/temperature/set?kelvin=500");
+    assert_eq!(get_body(&state, "/temperature/get"), "1000");
+
+    get_body(&state, "/temperature/set?kelvin=6400");
+    get_body(&state, "/cooler");
+    assert_eq!(get_body(&state, "/temperature/get"), "6500");
+}
+
+#[test]
+fn manual_changes_override_the_schedule() {
+    let (state, _mock) = app_state(200.0, 6500);
+    get_body(&state, "/darker");
+    assert!(with_data(&state, |data| data.manual_override));
+}
+
+#[test]
+fn manual_overrides_last_until_the_next_phase() {
+    let (state, mock) = app_state(200.0, 6500);
+    let schedule = config::ScheduleConfig {
+        latitude: 0.0,
+        longitude: 0.0,
+        day_brightness: 200.0,
+        day_temperature: 6500,
+        night_brightness: 100.0,
+        night_temperature: 3500,
+        update_interval: 30,
+    };
+    let mut last_phase = None;
+    let update = |last_phase: &mut _, elevation| {
+        assert!(scheduler::update(
+            &schedule,
+            &state.controller,
+            last_phase,
+            elevation
+        ));
+    };
+
+    // Already at the day values
+    update(&mut last_phase, 10.0);
+    assert_eq!(mock.take_calls(), vec![]);
+
+    get_body(&state, "/set?brightness=120");
+    mock.take_calls();
+    update(&mut last_phase, 20.0);
+    assert_eq!(get_body(&state, "/get"), "120");
+    assert!(with_data(&state, |data| data.manual_override));
+    assert_eq!(mock.take_calls(), vec![]);
+
+    // Halfway through twilight
+    update(&mut last_phase, -1.5);
+    assert!(!with_data(&state, |data| data.manual_override));
+    assert_eq!(get_body(&state, "/get"), "150");
+    assert_eq!(get_body(&state, "/temperature/get"), "5000");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(50.0), Call::SetGamma(1.0, 5000)]
+    );
+
+    // Too small a change to be worth applying
+    update(&mut last_phase, -1.49);
+    assert_eq!(mock.take_calls(), vec![]);
+    assert_eq!(get_body(&state, "/get"), "150");
+}
+
+#[test]
+fn changes_are_saved_to_the_state_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sunset/state.json");
+
+    let (state, _mock) = app_state(150.0, 6500);
+    let state_file = path.clone();
+    with_data(&state, |data| data.state_file = Some(state_file));
+
+    get_body(&state, "/set?brightness=80");
+    get_body(&state, "/temperature/set?kelvin=4000");
+
+    assert_eq!(
+        state::load(&path).unwrap(),
+        Some(state::PersistedState {
+            brightness: 80.0,
+            temperature: 4000,
+            manual_override: true,
+        })
+    );
+}
+
+#[test]
+fn set_with_duration_animates_towards_target() {
+    let (state, mock) = app_state(100.0, 6500);
+    with_data(&state, |data| data.transition.frame_rate = 50);
+
+    // Changes answer with where the brightness is heading, not where it is yet
+    assert_eq!(
+        get_body(&state, "/set?brightness=150&duration_ms=100"),
+        "150"
+    );
+    std::thread::sleep(Duration::from_millis(400));
+
+    assert_eq!(get_body(&state, "/get"), "150");
+    let backlights: Vec<f32> = mock
+        .take_calls()
+        .into_iter()
+        .filter_map(|call| match call {
+            Call::SetBacklight(value) => Some(value),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(backlights.len(), 5);
+    assert!(backlights.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(backlights.last(), Some(&50.0));
+}
+
+#[test]
+fn new_requests_cancel_running_transitions() {
+    let (state, mock) = app_state(100.0, 6500);
+
+    // Steps start from where the brightness is heading, not from the frame it's at
+    get_body(&state, "/set?brightness=150&duration_ms=10000");
+    assert_eq!(get_body(&state, "/get"), "100");
+    assert_eq!(get_body(&state, "/darker"), "145");
+    assert_eq!(get_body(&state, "/get"), "145");
+    mock.take_calls();
+    get_body(&state, "/set?brightness=200&duration_ms=10000");
+    get_body(&state, "/set?brightness=60");
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(0.10673), Call::SetGamma(0.6, 6500)]
+    );
+
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(get_body(&state, "/get"), "60");
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn concurrent_requests_are_applied_one_at_a_time() {
+    let (state, mock) = app_state(100.0, 6500);
+
+    let threads: Vec<_> = (0..10)
+        .map(|_| {
+            let state = state.clone();
+            std::thread::spawn(move || get_body(&state, "/brighter"))
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // No step got lost, and the backend saw every value in order
+    assert_eq!(get_body(&state, "/get"), "150");
+    let backlights: Vec<f32> = mock
+        .take_calls()
+        .into_iter()
+        .filter_map(|call| match call {
+            Call::SetBacklight(value) => Some(value),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        backlights,
+        vec![5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 35.0, 40.0, 45.0, 50.0]
+    );
+}
+
+fn send_json(
+    state: &web::Data<AppState>,
+    request: test::TestRequest,
+) -> (StatusCode, serde_json::Value) {
+    let mut app = test::init_service(App::new().register_data(state.clone()).configure(routes));
+    let response = test::call_service(&mut app, request.to_request());
+    let status = response.status();
+    let body = test::read_body(response);
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[test]
+fn api_get_returns_json_state() {
+    let (state, _mock) = app_state(150.0, 4000);
+    let (status, body) = send_json(&state, test::TestRequest::get().uri("/api/v1/state"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "brightness": 150.0,
+            "backlight": 50.0,
+            "gamma": 1.0,
+            "temperature": 4000,
+            "manual_override": false,
+            "backend": { "healthy": true },
+        })
+    );
+}
+
+#[test]
+fn api_state_includes_redshift_status() {
+    let mock = Mock::new(50.0).with_redshift(RedshiftStatus {
+        running: false,
+        pid: None,
+        restarts: 3,
+        last_error: Some("redshift exited with exit status: 1: No such output".into()),
+    });
+    let state = app_state_with(mock, 150.0, 4000, BTreeMap::new());
+    let (status, body) = send_json(&state, test::TestRequest::get().uri("/api/v1/state"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body["redshift"],
+        serde_json::json!({
+            "running": false,
+            "restarts": 3,
+            "last_error": "redshift exited with exit status: 1: No such output",
+        })
+    );
+}
+
+#[test]
+fn health_reports_what_is_broken() {
+    let (state, mock) = app_state(150.0, 6500);
+    let state = web::Data::new(AppState {
+        controller: state.controller.clone(),
+        brightness_step: 5.0,
+        temperature_step: 250,
+        backend_config: BackendConfig {
+            light_command: "/nonexistent/light".into(),
+            gamma: backend::GammaKind::Redshift,
+            redshift_command: "/nonexistent/redshift".into(),
+            ..BackendConfig::default()
+        },
+    });
+
+    let (status, body) = send_json(&state, test::TestRequest::get().uri("/health"));
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let health: api::Health = serde_json::from_value(body).unwrap();
+    assert!(!health.healthy);
+    let checks: Vec<_> = health
+        .checks
+        .iter()
+        .map(|check| (check.name.as_str(), check.status))
+        .collect();
+    assert_eq!(
+        checks,
+        [
+            ("light", CheckStatus::Error),
+            ("redshift", CheckStatus::Error),
+            ("backend", CheckStatus::Ok),
+        ]
+    );
+    assert_eq!(
+        health.checks[1].hint.as_deref(),
+        Some("Install redshift, or point backend.redshift_command at it")
+    );
+
+    // Probing doesn't touch the screen
+    assert_eq!(mock.take_calls(), []);
+}
+
+#[test]
+fn api_patch_changes_only_given_values() {
+    let (state, mock) = app_state(150.0, 6500);
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::patch()
+            .uri("/api/v1/state")
+            .set_json(&serde_json::json!({ "brightness": 50 })),
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["brightness"], 50.0);
+    assert_eq!(body["gamma"], 0.5);
+    assert_eq!(body["temperature"], 6500);
+    assert_eq!(body["manual_override"], true);
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(0.10673), Call::SetGamma(0.5, 6500)]
+    );
+
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::patch()
+            .uri("/api/v1/state")
+            .set_json(&serde_json::json!({ "temperature": 3000 })),
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["brightness"], 50.0);
+    assert_eq!(body["temperature"], 3000);
+}
+
+#[test]
+fn api_put_requires_the_whole_state() {
+    let (state, mock) = app_state(150.0, 6500);
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::put()
+            .uri("/api/v1/state")
+            .set_json(&serde_json::json!({ "brightness": 50 })),
+    );
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].as_str().unwrap().contains("PATCH"));
+    assert_eq!(mock.take_calls(), vec![]);
+
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::put()
+            .uri("/api/v1/state")
+            .set_json(&serde_json::json!({ "brightness": 250, "temperature": 500 })),
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["brightness"], 200.0);
+    assert_eq!(body["temperature"], 1000);
+}
+
+#[test]
+fn api_rejects_invalid_requests_with_json_errors() {
+    let (state, mock) = app_state(150.0, 6500);
+
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::patch()
+            .uri("/api/v1/state")
+            .set_json(&serde_json::json!({ "brightnes": 50 })),
+    );
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].is_string());
+    assert_eq!(body["kind"], "invalid_value");
+
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::patch()
+            .uri("/api/v1/state")
+            .header("content-type", "application/json")
+            .set_payload("{ not json"),
+    );
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].is_string());
+    assert_eq!(body["kind"], "invalid_value");
+
+    let (status, body) = send_json(&state, test::TestRequest::delete().uri("/api/v1/state"));
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert!(body["error"].is_string());
+    assert_eq!(body["kind"], "method_not_allowed");
+
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn backend_failures_are_reported_as_json() {
+    let (state, mock) = app_state(150.0, 6500);
+
+    mock.fail_with(Some(AppError::CommandFailed {
+        command: "light".into(),
+        status: Some(1),
+        stderr: "No backlight controller".into(),
+    }));
+    let (status, body) = send_json(&state, test::TestRequest::get().uri("/set?brightness=120"));
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["kind"], "command_failed");
+    assert_eq!(body["command"], "light");
+    assert_eq!(body["status"], 1);
+    assert_eq!(body["stderr"], "No backlight controller");
+    assert_eq!(
+        body["error"],
+        "light exited with status 1: No backlight controller"
+    );
+
+    mock.fail_with(Some(AppError::BackendUnavailable(
+        "Could not run redshift: No such file or directory".into(),
+    )));
+    let (status, body) = send_json(&state, test::TestRequest::get().uri("/warmer"));
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["kind"], "backend_unavailable");
+
+    let (status, body) = send_json(
+        &state,
+        test::TestRequest::patch()
+            .uri("/api/v1/state")
+            .set_json(&serde_json::json!({ "temperature": 4000 })),
+    );
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["kind"], "backend_unavailable");
+
+    mock.fail_with(Some(AppError::Timeout {
+        command: "light".into(),
+        timeout: Duration::from_secs(3),
+    }));
+    let (status, body) = send_json(&state, test::TestRequest::get().uri("/brighter"));
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(body["kind"], "timeout");
+    assert_eq!(body["error"], "light did not finish within 3000 ms");
+
+    mock.fail_with(None);
+    get_body(&state, "/set?brightness=130");
+    assert_eq!(get_body(&state, "/get"), "130");
+}
+
+#[test]
+fn events_stream_state_changes() {
+    use futures::Stream;
+
+    let (state, _mock) = app_state(100.0, 6500);
+    let mut response = get(&state, "/events");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+
+    let mut body = response.take_body();
+    let mut next_event = || {
+        let chunk = test::block_on(futures::future::poll_fn(|| body.poll())).unwrap();
+        String::from_utf8(chunk.unwrap().to_vec()).unwrap()
+    };
+
+    // The current state comes first, then every change from any source
+    assert_eq!(
+        next_event(),
+        "data: {\"brightness\":100.0,\"temperature\":6500,\"manual_override\":false}\n\n"
+    );
+    get_body(&state, "/set?brightness=150");
+    assert_eq!(
+        next_event(),
+        "data: {\"brightness\":150.0,\"temperature\":6500,\"manual_override\":true}\n\n"
+    );
+    get_body(&state, "/warmer");
+    assert_eq!(
+        next_event(),
+        "data: {\"brightness\":150.0,\"temperature\":6250,\"manual_override\":true}\n\n"
+    );
+}
+
+#[test]
+fn external_backlight_changes_are_picked_up() {
+    let (state, mock) = app_state(150.0, 6500);
+    let check = |mut watcher: watcher::Watcher| {
+        with_data(&state, move |data| {
+            watcher.check(data);
+            watcher
+        })
+    };
+    let watcher = check(watcher::Watcher::default());
+
+    // Our own changes move the backlight too, but aren't external
+    get_body(&state, "/set?brightness=160");
+    mock.take_calls();
+    let watcher = check(watcher);
+    assert_eq!(get_body(&state, "/get"), "160");
+
+    with_data(&state, |data| data.manual_override = false);
+    mock.change_backlight(80.0);
+    let watcher = check(watcher);
+    assert_eq!(get_body(&state, "/get"), "180");
+    assert!(with_data(&state, |data| data.manual_override));
+    // The backlight is left alone, only gamma gets updated
+    assert_eq!(mock.take_calls(), vec![Call::SetGamma(1.0, 6500)]);
+
+    check(watcher);
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn setting_the_main_output_is_not_an_external_change() {
+    let mock = Mock::new(50.0)
+        .with_outputs(&[("intel_backlight", true, false)])
+        .with_main_output("intel_backlight");
+    let state = app_state_with(mock.clone(), 150.0, 6500, BTreeMap::new());
+    let check = |mut watcher: watcher::Watcher| {
+        with_data(&state, move |data| {
+            watcher.check(data);
+            watcher
+        })
+    };
+    let watcher = check(watcher::Watcher::default());
+
+    get_body(&state, "/outputs/intel_backlight/set?brightness=120");
+    mock.take_calls();
+    let watcher = check(watcher);
+    check(watcher);
+    assert_eq!(get_body(&state, "/get"), "150");
+    assert_eq!(get_body(&state, "/outputs/intel_backlight/get"), "120");
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn shutdown_restores_the_screen_when_asked() {
+    let (state, mock) = app_state(150.0, 3000);
+    with_data(&state, |data| data.shutdown(None));
+    assert_eq!(mock.take_calls(), vec![Call::Shutdown]);
+
+    let (state, mock) = app_state(150.0, 3000);
+    // Slow enough that no frame runs before the shutdown
+    with_data(&state, |data| data.transition.frame_rate = 1);
+    get_body(&state, "/set?brightness=200&duration_ms=10000");
+    state
+        .controller
+        .stop(|data| {
+            data.shutdown(Some(35.0));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(
+        mock.take_calls(),
+        vec![
+            Call::SetBacklight(35.0),
+            Call::SetGamma(1.0, 6500),
+            Call::Shutdown
+        ]
+    );
+
+    // Nothing changes the screen after that, neither the transition nor new requests
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        get(&state, "/brighter").status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(mock.take_calls(), vec![]);
+}
+
+#[test]
+fn outputs_can_be_set_individually_and_in_groups() {
+    let mock = Mock::new(50.0).with_outputs(&[
+        ("intel_backlight", true, false),
+        ("eDP-1", false, true),
+        ("HDMI-A-1", false, true),
+    ]);
+    let mut groups = BTreeMap::new();
+    groups.insert(
+        "laptop".to_string(),
+        vec!["intel_backlight".to_string(), "eDP-1".to_string()],
+    );
+    let state = app_state_with(mock.clone(), 150.0, 6500, groups);
+
+    let (status, outputs) = send_json(&state, test::TestRequest::get().uri("/outputs"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(outputs["outputs"].as_array().unwrap().len(), 3);
+    assert_eq!(outputs["outputs"][1]["name"], "eDP-1");
+    assert_eq!(outputs["outputs"][1]["gamma"], true);
+    assert_eq!(outputs["outputs"][1]["individual"], false);
+
+    // An external monitor with gamma only, dimmed on its own
+    get_body(&state, "/outputs/HDMI-A-1/set?brightness=50");
+    assert_eq!(
+        mock.take_calls(),
+        vec![
+            Call::SetBacklight(50.0),
+            Call::SetGamma(1.0, 6500),
+            Call::SetOutputGamma("HDMI-A-1".to_string(), 0.5, 6500),
+        ]
+    );
+    assert_eq!(get_body(&state, "/outputs/HDMI-A-1/get"), "50");
+    assert_eq!(get_body(&state, "/outputs/eDP-1/get"), "150");
+
+    // Both halves of the laptop screen at once
+    get_body(&state, "/outputs/laptop/set?brightness=120");
+    let calls = mock.take_calls();
+    assert!(calls.contains(&Call::SetOutputBacklight(
+        "intel_backlight".to_string(),
+        20.0
+    )));
+    assert!(calls.contains(&Call::SetOutputGamma("eDP-1".to_string(), 1.0, 6500)));
+    assert_eq!(get_body(&state, "/outputs/laptop/get"), "120");
+
+    // Temperature changes keep individual values
+    get_body(&state, "/warmer");
+    assert!(mock
+        .take_calls()
+        .contains(&Call::SetOutputGamma("HDMI-A-1".to_string(), 0.5, 6250)));
+
+    get_body(&state, "/outputs/HDMI-A-1/reset");
+    assert_eq!(get_body(&state, "/outputs/HDMI-A-1/get"), "150");
+    mock.take_calls();
+
+    // Changing everything brings every output back in line
+    get_body(&state, "/set?brightness=180");
+    assert_eq!(get_body(&state, "/outputs/laptop/get"), "180");
+    assert_eq!(
+        mock.take_calls(),
+        vec![
+            Call::SetBacklight(80.0),
+            Call::SetGamma(1.0, 6250),
+            Call::SetOutputBacklight("intel_backlight".to_string(), 80.0),
+        ]
+    );
+
+    // Released outputs keep following global changes
+    get_body(&state, "/brighter");
+    assert!(mock.take_calls().contains(&Call::SetOutputBacklight(
+        "intel_backlight".to_string(),
+        85.0
+    )));
+
+    assert_eq!(
+        get(&state, "/outputs/DP-3/set?brightness=100").status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[test]
+fn requests_need_the_bearer_token() {
+    let (state, mock) = app_state(150.0, 6500);
+    let mut app = test::init_service(
+        App::new()
+            .wrap(auth::BearerAuth::new(Some("s3cret")))
+            .register_data(state.clone())
+            .configure(routes),
+    );
+
+    let response = test::call_service(
+        &mut app,
+        test::TestRequest::get()
+            .uri("/set?brightness=20")
+            .to_request(),
+    );
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Bearer"
+    );
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response)).unwrap();
+    assert_eq!(body["kind"], "unauthorized");
+
+    let response = test::call_service(
+        &mut app,
+        test::TestRequest::get()
+            .uri("/set?brightness=20")
+            .header("authorization", "Bearer wrong")
+            .to_request(),
+    );
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(mock.take_calls(), vec![]);
+
+    let response = test::call_service(
+        &mut app,
+        test::TestRequest::get()
+            .uri("/set?brightness=20")
+            .header("authorization", "Bearer s3cret")
+            .to_request(),
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(0.10673), Call::SetGamma(0.2, 6500)]
+    );
+}
+
+#[test]
+fn generated_token_file_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let server = config::ServerConfig {
+        token_file: dir.path().join("sunset/token"),
+        ..Default::default()
+    };
+
+    let token = auth::load_or_create_token(&server).unwrap().unwrap();
+    assert_eq!(token.len(), 64);
+    let mode = std::fs::metadata(&server.token_file)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // The daemon reuses the token on restart, and clients read the same one
+    assert_eq!(
+        auth::load_or_create_token(&server).unwrap(),
+        Some(token.clone())
+    );
+    assert_eq!(auth::client_token(&server).unwrap(), Some(token));
+
+    std::fs::set_permissions(&server.token_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(auth::load_or_create_token(&server).is_err());
+}
+
+/// A dbus-daemon of our own, so tests don't touch the real session bus
+struct PrivateBus {
+    process: std::process::Child,
+    address: String,
+    path: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl PrivateBus {
+    /// Returns None when dbus-daemon isn't installed
+    fn start() -> Option<PrivateBus> {
+        use std::io::BufRead;
+        use std::process::{Command, Stdio};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bus");
+        let mut process = Command::new("dbus-daemon")
+            .arg("--session")
+            .arg("--nofork")
+            .arg("--print-address")
+            .arg(format!("--address=unix:path={}", path.display()))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+
+        // The address gets printed once the bus is ready
+        let mut address = String::new();
+        std::io::BufReader::new(process.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(PrivateBus {
+            process,
+            address: address.trim().to_string(),
+            path,
+            _dir: dir,
+        })
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Calls a method on the daemon's D-Bus object, returning the reply
+fn dbus_call<B>(client: &zbus::Connection, method: &str, body: &B) -> f64
+where
+    B: serde::Serialize + zbus::zvariant::Type,
+{
+    client
+        .call_method(
+            Some(dbus::BUS_NAME),
+            dbus::OBJECT_PATH,
+            Some("org.sunset.Brightness"),
+            method,
+            body,
+        )
+        .unwrap()
+        .body()
+        .unwrap()
+}
+
+/// Waits for the next Changed signal, failing the test after a few seconds
+fn next_changed(client: &zbus::Connection) -> f64 {
+    client
+        .receive_specific(|message| Ok(message.header()?.member()? == Some("Changed")))
+        .expect("no Changed signal")
+        .body()
+        .unwrap()
+}
+
+#[test]
+fn dbus_service_controls_brightness() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => {
+            eprintln!("dbus-daemon not found, skipping");
+            return;
+        }
+    };
+    let (state, mock) = app_state(100.0, 6500);
+    dbus::spawn(Some(&bus.address), state.controller.clone(), 5.0).unwrap();
+
+    let stream = std::os::unix::net::UnixStream::connect(&bus.path).unwrap();
+    let timeout_handle = stream.try_clone().unwrap();
+    let client = zbus::Connection::new_unix_client(stream, true).unwrap();
+    timeout_handle
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    zbus::fdo::DBusProxy::new(&client)
+        .unwrap()
+        .add_match("type='signal',interface='org.sunset.Brightness',member='Changed'")
+        .unwrap();
+
+    assert_eq!(dbus_call(&client, "Get", &()), 100.0);
+
+    assert_eq!(dbus_call(&client, "Set", &150.0f64), 150.0);
+    assert_eq!(
+        mock.take_calls(),
+        vec![Call::SetBacklight(50.0), Call::SetGamma(1.0, 6500)]
+    );
+    assert_eq!(next_changed(&client), 150.0);
+
+    // Changes made through other interfaces are announced too
+    get_body(&state, "/darker");
+    assert_eq!(next_changed(&client), 145.0);
+
+    assert_eq!(dbus_call(&client, "Step", &-2i32), 135.0);
+    assert_eq!(next_changed(&client), 135.0);
+    assert_eq!(dbus_call(&client, "Set", &500.0f64), 200.0);
+    assert_eq!(next_changed(&client), 200.0);
+}
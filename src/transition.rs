@@ -0,0 +1,103 @@
+// Animated brightness changes.
+//
+// A transition steps the brightness towards its target once per frame. Frames are run by the
+// controller between jobs, so a new change simply replaces (or cancels) the running transition
+// before its next frame.
+
+use crate::error::AppError;
+use crate::AppData;
+use log::warn;
+use std::time::{Duration, Instant};
+
+/// A transition in progress
+pub struct Transition {
+    from: f32,
+    to: f32,
+    frames: u32,
+    /// Frames applied so far
+    frame: u32,
+    frame_time: Duration,
+    next_frame: Instant,
+}
+
+/// Interpolates brightness in a perceptual space (roughly CIE lightness, which goes with the cube
+/// root of luminance), so the animation looks evenly paced at both ends of the scale
+fn interpolate(from: f32, to: f32, progress: f32) -> f32 {
+    let (from, to) = (from.cbrt(), to.cbrt());
+    (from + (to - from) * progress).powi(3)
+}
+
+/// Stops any running transition
+pub fn cancel(data: &mut AppData) {
+    data.running_transition = None;
+}
+
+/// Moves the brightness to `target` over `duration`, or over the configured default duration if
+/// none is given. Only changes applied right away can report backend errors: the frames of an
+/// animated transition just log them.
+pub fn start(data: &mut AppData, target: f32, duration: Option<Duration>) -> Result<(), AppError> {
+    cancel(data);
+    // This is a change for every output, including those set on their own
+    data.release_outputs();
+
+    let duration = duration.unwrap_or(data.transition.duration);
+    let from = data.brightness.value;
+    let to = data.brightness.clamped(target);
+
+    let frames = (duration.as_secs_f32() * data.transition.frame_rate as f32).round() as u32;
+    if frames <= 1 || from == to {
+        data.brightness.set(to);
+        return data.restart();
+    }
+
+    let frame_time = duration / frames;
+    data.running_transition = Some(Transition {
+        from,
+        to,
+        frames,
+        frame: 0,
+        frame_time,
+        next_frame: Instant::now() + frame_time,
+    });
+    Ok(())
+}
+
+/// Where the brightness is heading: the end of the running transition, or the current value
+pub fn target(data: &AppData) -> f32 {
+    data.running_transition
+        .as_ref()
+        .map_or(data.brightness.value, |transition| transition.to)
+}
+
+/// When the next frame of the running transition is due, if there is one
+pub fn next_frame(data: &AppData) -> Option<Instant> {
+    data.running_transition
+        .as_ref()
+        .map(|transition| transition.next_frame)
+}
+
+/// Applies the next frame of the running transition
+pub fn step(data: &mut AppData) {
+    let transition = match &mut data.running_transition {
+        Some(transition) => transition,
+        None => return,
+    };
+    transition.frame += 1;
+    transition.next_frame += transition.frame_time;
+
+    let result = if transition.frame >= transition.frames {
+        let to = transition.to;
+        data.running_transition = None;
+        data.brightness.set(to);
+        // Only the final value is worth persisting
+        data.restart()
+    } else {
+        let progress = transition.frame as f32 / transition.frames as f32;
+        let value = interpolate(transition.from, transition.to, progress);
+        data.brightness.set(value);
+        data.apply()
+    };
+    if let Err(err) = result {
+        warn!("Could not apply transition frame: {}", err);
+    }
+}
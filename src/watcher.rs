@@ -0,0 +1,78 @@
+// Notices backlight changes made behind our back (hardware keys, `light`, other tools), and takes
+// them as the new brightness instead of overwriting them on the next change.
+//
+// sysfs doesn't send inotify events when the brightness changes, so this polls the backend.
+
+use crate::controller::Controller;
+use crate::{transition, AppData};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Backlight readings closer than this (in percent) to the last one are not a change
+const BACKLIGHT_EPSILON: f32 = 0.1;
+
+/// What the watcher saw last time around
+#[derive(Default)]
+pub struct Watcher {
+    /// `AppData::applied` and the backlight reading at the last check
+    last: Option<(u64, f32)>,
+}
+
+impl Watcher {
+    /// Reads the backlight, and updates the brightness if something else changed it since the
+    /// last check
+    pub fn check(&mut self, data: &mut AppData) {
+        let reading = match data.backend.read_backlight() {
+            Ok(reading) => reading,
+            Err(err) => {
+                warn!("Could not read the backlight: {}", err);
+                return;
+            }
+        };
+
+        let external = match self.last {
+            // The backlight is expected to move when we applied something ourselves, including
+            // values for single outputs (which may well be the main backlight device)
+            Some((last_applied, last_reading)) => {
+                last_applied == data.applied && (reading - last_reading).abs() > BACKLIGHT_EPSILON
+            }
+            None => false,
+        };
+
+        if external {
+            info!("Backlight changed to {:.1}% by another program", reading);
+            data.brightness.set_from_light(reading);
+            // Stop transitions and the schedule from undoing the user's change
+            transition::cancel(data);
+            data.manual_override = true;
+            let (gamma, temperature) = (data.brightness.to_redshift(), data.temperature.value);
+            if let Err(err) = data.backend.set_gamma(gamma, temperature) {
+                warn!("Could not update gamma: {}", err);
+            }
+            data.save_state();
+            data.notify();
+        }
+        self.last = Some((data.applied, reading));
+    }
+}
+
+pub fn spawn(interval: Duration, controller: Controller) {
+    std::thread::Builder::new()
+        .name("watcher".into())
+        .spawn(move || {
+            let mut watcher = Watcher::default();
+            loop {
+                // The watcher goes over to the controller thread and back on every check
+                watcher = match controller.run(move |data| {
+                    watcher.check(data);
+                    Ok(watcher)
+                }) {
+                    Ok(watcher) => watcher,
+                    // Shutting down
+                    Err(_) => return,
+                };
+                std::thread::sleep(interval);
+            }
+        })
+        .expect("Could not start backlight watcher thread");
+}